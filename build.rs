@@ -9,12 +9,27 @@
 // You should have received a copy of the MIT License along with this software.
 // If not, see <https://opensource.org/licenses/MIT>.
 
+#[macro_use]
+extern crate amplify;
+
 use std::{env, fs};
 
 use clap::IntoApp;
 use clap_complete::generate_to;
 use clap_complete::shells::*;
 
+pub mod acl {
+    include!("src/acl.rs");
+}
+
+pub mod durability {
+    include!("src/durability.rs");
+}
+
+pub mod backend {
+    include!("src/backend/kind.rs");
+}
+
 pub mod stored {
     include!("src/opts.rs");
 }
@@ -28,9 +43,10 @@ fn main() -> Result<(), configure_me_codegen::Error> {
         generate_to(Bash, &mut app, &name, outdir)?;
         generate_to(PowerShell, &mut app, &name, outdir)?;
         generate_to(Zsh, &mut app, &name, outdir)?;
-
-        // configure_me_codegen::build_script_auto()
     }
 
-    Ok(())
+    // `build_script_auto` fails to parse manifests of edition 2021 crates, so
+    // we provide path to the spec explicitly
+    #[allow(deprecated)]
+    configure_me_codegen::build_script("config_spec.toml")
 }
@@ -9,13 +9,14 @@
 // You should have received a copy of the MIT License along with this software.
 // If not, see <https://opensource.org/licenses/MIT>.
 
+use internet2::addr::ServiceAddr;
 use microservices::cli;
 use microservices::rpc::ServerError;
 use microservices::shell::Exec;
-use store_rpc::{Client, FailureCode};
+use store_rpc::{Client, FailureCode, Subscriber, LIST_IDS_PAGE_LIMIT};
 use storm::Chunk;
 
-use crate::{Command, Opts};
+use crate::{Command, Opts, SetCommand};
 
 impl Exec for Opts {
     type Client = Client;
@@ -35,6 +36,30 @@ impl Exec for Opts {
                     println!("{}", table);
                 }
             }
+            Command::Drop { table } => {
+                client.drop_table(table)?;
+                eprintln!("success");
+            }
+            Command::Clear { table } => {
+                client.clear_table(table)?;
+                eprintln!("success");
+            }
+            Command::Rename { from, to } => {
+                client.rename_table(from, to)?;
+                eprintln!("success");
+            }
+            Command::Flush => {
+                client.flush()?;
+                eprintln!("success");
+            }
+            Command::Backup { path } => {
+                client.backup(path)?;
+                eprintln!("success");
+            }
+            Command::Shutdown => {
+                client.shutdown()?;
+                eprintln!("success");
+            }
             Command::Count { table } => {
                 eprint!("Database table `{}` contains ", table);
                 eprintln!("{} object(s)", client.count(table)?);
@@ -50,6 +75,13 @@ impl Exec for Opts {
                 eprint!("Stored chunk id ");
                 println!("{}", chunk_id);
             }
+            Command::StoreContent { table, file } => {
+                let data = cli::read_file_or_stdin(file).expect("unable to read the file");
+                let chunk = Chunk::try_from(&data)?;
+                let chunk_id = client.store_content(table, &chunk)?;
+                eprint!("Stored chunk id ");
+                println!("{}", chunk_id);
+            }
             Command::Retrieve { table, key, output } => match client.retrieve_chunk(table, key)? {
                 Some(chunk) => {
                     eprintln!("success");
@@ -61,16 +93,41 @@ impl Exec for Opts {
                     cli::write_file_or_stdout(chunk, output).expect("unable to write to the file");
                     eprintln!("success");
                 }
-                None => {
-                    eprintln!("unknown chunk");
+                None => return Err(FailureCode::KeyAbsent.into()),
+            },
+            Command::Set { command } => command.exec(client)?,
+            Command::Watch {
+                table,
+                mut endpoint,
+            } => {
+                if let ServiceAddr::Ipc(ref mut path) = endpoint {
+                    *path = shellexpand::tilde(path).to_string();
+                }
+                eprintln!("Watching changes in tables matching `{}`:", table);
+                for event in Subscriber::with(&endpoint, &table)? {
+                    println!("{}", event?);
                 }
+            }
+            Command::Delete { table, key } => match client.remove(table, key)? {
+                Some(chunk_id) => {
+                    eprint!("Removed chunk id ");
+                    println!("{}", chunk_id);
+                }
+                None => return Err(FailureCode::KeyAbsent.into()),
             },
+            Command::Verify { table, quarantine } => {
+                let report = client.verify(table, quarantine)?;
+                eprintln!("success");
+                eprintln!("Checked {} entries, {} corrupted", report.checked, report.bad.len());
+                for entry in report.bad {
+                    println!("{}", entry);
+                }
+            }
             Command::Ids { table } => {
                 eprintln!("success");
                 eprintln!("Found ids:");
-                let ids = client.ids(table)?;
-                for id in ids {
-                    println!("{}", id);
+                for id in client.ids_iter(table, LIST_IDS_PAGE_LIMIT) {
+                    println!("{}", id?);
                 }
             }
         }
@@ -78,3 +135,35 @@ impl Exec for Opts {
         Ok(())
     }
 }
+
+impl SetCommand {
+    fn exec(self, client: &mut Client) -> Result<(), ServerError<FailureCode>> {
+        match self {
+            SetCommand::Insert { table, key, item } => {
+                client.insert_into_set(table, key, item)?;
+                eprintln!("success");
+            }
+            SetCommand::Remove { table, key, item } => {
+                if client.remove_from_set(table, key, item)? {
+                    eprintln!("success");
+                } else {
+                    eprintln!("item is not a member of the set");
+                }
+            }
+            SetCommand::Contains { table, key, item } => {
+                println!("{}", client.set_contains(table, key, item)?);
+            }
+            SetCommand::Members { table, key } => {
+                eprintln!("Set members:");
+                for item in client.set_members(table, key)? {
+                    println!("{}", item);
+                }
+            }
+            SetCommand::Count { table, key } => {
+                eprint!("Set contains ");
+                eprintln!("{} item(s)", client.set_len(table, key)?);
+            }
+        }
+        Ok(())
+    }
+}
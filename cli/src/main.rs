@@ -23,13 +23,16 @@ extern crate clap;
 mod command;
 mod opts;
 
+use std::path::PathBuf;
+
 use clap::Parser;
 use internet2::addr::ServiceAddr;
 use microservices::cli::LogStyle;
 use microservices::shell::{Exec, LogLevel};
 use store_rpc::client::Client;
+use store_rpc::read_or_create_key;
 
-pub use crate::opts::{Command, Opts};
+pub use crate::opts::{Command, Opts, SetCommand};
 
 fn main() {
     println!("store-cli: command-line tool for working with Store daemon");
@@ -38,13 +41,24 @@ fn main() {
     LogLevel::from_verbosity_flag_count(opts.verbose).apply();
     trace!("Command-line arguments: {:#?}", &opts);
 
-    let connect = &mut opts.connect;
-    if let ServiceAddr::Ipc(ref mut path) = connect {
-        *path = shellexpand::tilde(path).to_string();
+    let mut client = match opts.secure {
+        Some(remote) => {
+            let key_file =
+                PathBuf::from(shellexpand::tilde(&opts.key_file.to_string_lossy()).as_ref());
+            let local = read_or_create_key(&key_file).expect("Error reading client key");
+            eprintln!("Connecting to {} as {}", remote, local.node_id());
+            Client::with_encrypted(local.private_key(), remote)
+        }
+        None => {
+            let connect = &mut opts.connect;
+            if let ServiceAddr::Ipc(ref mut path) = connect {
+                *path = shellexpand::tilde(path).to_string();
+            }
+            debug!("RPC socket {}", connect);
+            Client::with(connect)
+        }
     }
-    debug!("RPC socket {}", connect);
-
-    let mut client = Client::with(connect).expect("Error initializing client");
+    .expect("Error initializing client");
 
     trace!("Executing command: {}", opts.command);
     opts.exec(&mut client)
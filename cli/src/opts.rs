@@ -12,9 +12,14 @@
 use std::path::PathBuf;
 
 use amplify::Slice32;
-use internet2::addr::ServiceAddr;
+use clap::ValueHint;
+use internet2::addr::{NodeAddr, ServiceAddr};
 use store_rpc::STORED_RPC_ENDPOINT;
 
+/// Default location of the file with client secret key used for encrypted
+/// sessions
+pub const STORE_CLI_KEY: &str = "~/.storm_node/store-cli.key";
+
 /// Command-line tool for working with store daemon
 #[derive(Parser, Clone, PartialEq, Eq, Debug)]
 #[clap(name = "store-cli", bin_name = "store-cli", author, version)]
@@ -34,6 +39,28 @@ pub struct Opts {
     )]
     pub connect: ServiceAddr,
 
+    /// Connect to the daemon over encrypted and authenticated session.
+    ///
+    /// The address must be given in form of `<node_id>@<host>:<port>`, where
+    /// `node_id` is the public key of the daemon. If given, overrides `--rpc`
+    /// option.
+    #[clap(short = 'S', long, global = true, env = "STORE_CLI_SECURE")]
+    pub secure: Option<NodeAddr>,
+
+    /// File with the client secret key used for encrypted sessions.
+    ///
+    /// If the file does not exist, a new key is generated and saved into it.
+    /// The daemon must have public key of the client in its allow-list.
+    #[clap(
+        short,
+        long = "key",
+        global = true,
+        default_value = STORE_CLI_KEY,
+        env = "STORE_CLI_KEY",
+        value_hint = ValueHint::FilePath
+    )]
+    pub key_file: PathBuf,
+
     /// Set verbosity level.
     ///
     /// Can be used multiple times to increase verbosity.
@@ -54,15 +81,69 @@ pub enum Command {
         table: String,
     },
 
-    /// List used database tables
+    /// List database tables, both used and existing in the storage
     Tables,
 
+    /// Remove database table together with all its data
+    #[display("drop '{table}'")]
+    Drop {
+        /// Database table to remove
+        table: String,
+    },
+
+    /// Remove all data from a database table, keeping the table itself
+    #[display("clear '{table}'")]
+    Clear {
+        /// Database table to clear
+        table: String,
+    },
+
+    /// Rename database table
+    #[display("rename '{from}' '{to}'")]
+    Rename {
+        /// Database table to rename
+        from: String,
+
+        /// New name for the table. There must be no table with this name.
+        to: String,
+    },
+
+    /// Flush all data written so far to disk
+    #[display("flush")]
+    Flush,
+
+    /// Stop the daemon, flushing all data to disk
+    #[display("shutdown")]
+    Shutdown,
+
+    /// Write snapshot of all tables into a backup archive.
+    ///
+    /// The archive is written by the daemon into the `backups` directory
+    /// inside its data directory.
+    #[display("backup '{path}'")]
+    Backup {
+        /// Path to the archive file relative to the backup directory. Existing
+        /// file is overwritten.
+        path: String,
+    },
+
     /// Count number of stored items
     Count {
         /// Database table to store file in
         table: String,
     },
 
+    /// Check integrity of all entries in a table, listing the corrupted ones
+    #[display("verify '{table}'")]
+    Verify {
+        /// Database table to check
+        table: String,
+
+        /// Move corrupted entries into the `_corrupt` table.
+        #[clap(long)]
+        quarantine: bool,
+    },
+
     /// List all chunk ids stored in a table
     Ids {
         /// Database table to store file in
@@ -83,6 +164,17 @@ pub enum Command {
         file: Option<PathBuf>,
     },
 
+    /// Stores file into content-addressed table under its chunk id
+    #[display("store-content '{table}' '{file:?}'")]
+    StoreContent {
+        /// Content-addressed table to store file in
+        table: String,
+
+        /// File to put into database. If no file is given, data are read from
+        /// STDIN.
+        file: Option<PathBuf>,
+    },
+
     /// Retrieves file from the database and outputs it into the provided
     /// file name, or onto stdout if no output file is specified.
     ///
@@ -98,4 +190,100 @@ pub enum Command {
         /// File for output. The data are printed to stdout if no file is given.
         output: Option<PathBuf>,
     },
+
+    /// Prints notifications about changes in the tables as they happen
+    #[display("watch '{table}'")]
+    Watch {
+        /// Table name or prefix of table names to watch. Empty string watches
+        /// all the tables.
+        table: String,
+
+        /// ZMQ socket on which the daemon publishes change notifications.
+        ///
+        /// Socket can be either TCP address in form of `<ipv4 | ipv6>:<port>` –
+        /// or a path to an IPC file.
+        #[clap(short = 'P', long = "pub", env = "STORED_PUB_ENDPOINT")]
+        endpoint: ServiceAddr,
+    },
+
+    /// Operations with sets of items stored under a key
+    #[display("set {command}")]
+    Set {
+        /// Set command to execute
+        #[clap(subcommand)]
+        command: SetCommand,
+    },
+
+    /// Removes object from the database.
+    #[display("delete '{table}' {key}")]
+    Delete {
+        /// Database table to remove object from.
+        table: String,
+
+        /// Object identifier used for store.
+        key: Slice32,
+    },
+}
+
+/// Commands working with sets stored under a key:
+#[derive(Subcommand, Clone, PartialEq, Eq, Debug, Display)]
+pub enum SetCommand {
+    /// Adds item to the set
+    #[display("insert '{table}' {key} {item}")]
+    Insert {
+        /// Database table containing the set.
+        table: String,
+
+        /// Key under which the set is stored.
+        key: Slice32,
+
+        /// Item to add to the set.
+        item: Slice32,
+    },
+
+    /// Removes item from the set
+    #[display("remove '{table}' {key} {item}")]
+    Remove {
+        /// Database table containing the set.
+        table: String,
+
+        /// Key under which the set is stored.
+        key: Slice32,
+
+        /// Item to remove from the set.
+        item: Slice32,
+    },
+
+    /// Checks whether item is a member of the set
+    #[display("contains '{table}' {key} {item}")]
+    Contains {
+        /// Database table containing the set.
+        table: String,
+
+        /// Key under which the set is stored.
+        key: Slice32,
+
+        /// Item to look for.
+        item: Slice32,
+    },
+
+    /// Lists all members of the set
+    #[display("members '{table}' {key}")]
+    Members {
+        /// Database table containing the set.
+        table: String,
+
+        /// Key under which the set is stored.
+        key: Slice32,
+    },
+
+    /// Counts members of the set
+    #[display("count '{table}' {key}")]
+    Count {
+        /// Database table containing the set.
+        table: String,
+
+        /// Key under which the set is stored.
+        key: Slice32,
+    },
 }
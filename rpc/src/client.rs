@@ -9,11 +9,11 @@
 // You should have received a copy of the MIT License along with this software.
 // If not, see <https://opensource.org/licenses/MIT>.
 
-use std::collections::BTreeSet;
+use std::collections::{btree_set, BTreeMap, BTreeSet};
 
 use amplify::Slice32;
-use internet2::addr::ServiceAddr;
-use internet2::session::LocalSession;
+use internet2::addr::{NodeAddr, ServiceAddr};
+use internet2::session::{BrontideSession, LocalSession};
 use internet2::{
     CreateUnmarshaller, SendRecvMessage, TypedEnum, Unmarshall, Unmarshaller, ZmqSocketType,
 };
@@ -22,12 +22,14 @@ use microservices::ZMQ_CONTEXT;
 use storm::{Chunk, ChunkId, TryFromChunk, TryToChunk};
 
 use crate::{
-    CheckUnknownReq, FailureCode, InsertReq, PrimaryKey, Reply, Request, RetrieveReq, StoreReq,
+    BackupReq, CasReq, CheckUnknownReq, FailureCode, IdsPage, IdsPartition, InsertReq, ListIdsReq,
+    PrimaryKey, RenameTableReq, Reply, Request, RetrieveBatchReq, RetrieveReq, StoreBatchReq,
+    StoreContentReq, StoreReq, TransactionReq, TxOp, TxResult, VerifyReport, VerifyReq,
 };
 
 pub struct Client {
     // TODO: Replace with RpcSession once its implementation is completed
-    session_rpc: LocalSession,
+    session_rpc: Box<dyn SendRecvMessage>,
     unmarshaller: Unmarshaller<Reply>,
 }
 
@@ -39,7 +41,25 @@ impl Client {
         let session_rpc =
             LocalSession::connect(ZmqSocketType::Req, connect, None, None, &ZMQ_CONTEXT)?;
         Ok(Self {
-            session_rpc,
+            session_rpc: Box::new(session_rpc),
+            unmarshaller: Reply::create_unmarshaller(),
+        })
+    }
+
+    /// Connects to the daemon over TCP using Brontide-encrypted session. The
+    /// daemon is authenticated by its public key, which is a part of `remote`
+    /// address; public key of the `local_key` must be present in the daemon
+    /// allow-list.
+    pub fn with_encrypted(
+        local_key: secp256k1::SecretKey,
+        remote: NodeAddr,
+    ) -> Result<Self, ServerError<FailureCode>> {
+        debug!("Initializing runtime");
+
+        trace!("Connecting to store daemon at {} using encrypted session", remote);
+        let session_rpc = BrontideSession::connect(local_key, remote)?;
+        Ok(Self {
+            session_rpc: Box::new(session_rpc),
             unmarshaller: Reply::create_unmarshaller(),
         })
     }
@@ -48,20 +68,71 @@ impl Client {
         self.request(Request::Use(table.to_string()))?.success_or_failure()
     }
 
+    pub fn drop_table(&mut self, table: impl ToString) -> Result<(), ServerError<FailureCode>> {
+        self.request(Request::DropTable(table.to_string()))?.success_or_failure()
+    }
+
+    pub fn clear_table(&mut self, table: impl ToString) -> Result<(), ServerError<FailureCode>> {
+        self.request(Request::ClearTable(table.to_string()))?.success_or_failure()
+    }
+
+    pub fn rename_table(
+        &mut self,
+        from: impl ToString,
+        to: impl ToString,
+    ) -> Result<(), ServerError<FailureCode>> {
+        let from = from.to_string();
+        let to = to.to_string();
+        self.request(Request::RenameTable(RenameTableReq { from, to }))?.success_or_failure()
+    }
+
+    /// Asks the daemon to shut down. The daemon replies once the request is
+    /// accepted; the shutdown itself happens asynchronously.
+    pub fn shutdown(&mut self) -> Result<(), ServerError<FailureCode>> {
+        self.request(Request::Shutdown)?.success_or_failure()
+    }
+
+    /// Makes all data written so far durable, independently of the durability
+    /// mode used by the daemon.
+    pub fn flush(&mut self) -> Result<(), ServerError<FailureCode>> {
+        self.request(Request::Flush)?.success_or_failure()
+    }
+
+    /// Writes consistent snapshot of all tables into a backup archive at `path`
+    /// inside the backup directory of the daemon.
+    pub fn backup(&mut self, path: impl ToString) -> Result<(), ServerError<FailureCode>> {
+        let path = path.to_string();
+        self.request(Request::Backup(BackupReq { path }))?.success_or_failure()
+    }
+
+    /// Checks integrity of all entries in the table, returning report on the
+    /// corrupted ones. If `quarantine` is set, the corrupted entries are moved
+    /// into the `_corrupt` table.
+    pub fn verify(
+        &mut self,
+        table: impl ToString,
+        quarantine: bool,
+    ) -> Result<VerifyReport, ServerError<FailureCode>> {
+        let table = table.to_string();
+        let request = Request::Verify(VerifyReq { table, quarantine });
+        self.request(request)?.extract(|reply| match reply {
+            Reply::Verified(report) => Some(report),
+            _ => None,
+        })
+    }
+
     pub fn list_tables(&mut self) -> Result<BTreeSet<String>, ServerError<FailureCode>> {
-        match self.request(Request::Tables)? {
-            Reply::Tables(tables) => Ok(tables),
-            Reply::Failure(failure) => Err(failure.into()),
-            _ => Err(ServerError::UnexpectedServerResponse),
-        }
+        self.request(Request::Tables)?.extract(|reply| match reply {
+            Reply::Tables(tables) => Some(tables),
+            _ => None,
+        })
     }
 
     pub fn count(&mut self, table: impl ToString) -> Result<u64, ServerError<FailureCode>> {
-        match self.request(Request::Count(table.to_string()))? {
-            Reply::Count(count) => Ok(count),
-            Reply::Failure(failure) => Err(failure.into()),
-            _ => Err(ServerError::UnexpectedServerResponse),
-        }
+        self.request(Request::Count(table.to_string()))?.extract(|reply| match reply {
+            Reply::Count(count) => Some(count),
+            _ => None,
+        })
     }
 
     pub fn store(
@@ -74,15 +145,77 @@ impl Client {
         let key = key.into_slice32();
         trace!("Store object with id {}", key);
         let chunk = data.try_to_chunk().map_err(|_| FailureCode::Encoding)?;
-        let reply = self.request(Request::Store(StoreReq { table, key, chunk }))?;
-        match reply {
-            Reply::ChunkId(chunk_id) => Ok(chunk_id),
-            Reply::Failure(failure) => {
-                warn!("Failure storing object with id {}", key);
-                Err(failure.into())
+        self.request(Request::Store(StoreReq { table, key, chunk }))?.extract(|reply| match reply {
+            Reply::ChunkId(chunk_id) => Some(chunk_id),
+            _ => None,
+        })
+    }
+
+    /// Stores object into a content-addressed table under its chunk id. Storing
+    /// an object which is already present in the table does nothing.
+    pub fn store_content(
+        &mut self,
+        table: impl ToString,
+        data: &impl TryToChunk,
+    ) -> Result<ChunkId, ServerError<FailureCode>> {
+        let table = table.to_string();
+        trace!("Store object into content-addressed table {}", table);
+        let chunk = data.try_to_chunk().map_err(|_| FailureCode::Encoding)?;
+        self.request(Request::StoreContent(StoreContentReq { table, chunk }))?.extract(|reply| {
+            match reply {
+                Reply::ChunkId(chunk_id) => Some(chunk_id),
+                _ => None,
             }
-            _ => Err(ServerError::UnexpectedServerResponse),
-        }
+        })
+    }
+
+    /// Stores object only if there is nothing stored under the key yet. If the
+    /// key is already used, returns id of the chunk stored under it as an
+    /// inner error.
+    pub fn store_if_absent(
+        &mut self,
+        table: impl ToString,
+        key: impl PrimaryKey,
+        data: &impl TryToChunk,
+    ) -> Result<Result<ChunkId, ChunkId>, ServerError<FailureCode>> {
+        let table = table.to_string();
+        let key = key.into_slice32();
+        trace!("Store object with id {} if absent", key);
+        let chunk = data.try_to_chunk().map_err(|_| FailureCode::Encoding)?;
+        self.request(Request::StoreIfAbsent(StoreReq { table, key, chunk }))?.extract(|reply| {
+            match reply {
+                Reply::ChunkId(chunk_id) => Some(Ok(chunk_id)),
+                Reply::Mismatch(Some(current)) => Some(Err(current)),
+                _ => None,
+            }
+        })
+    }
+
+    /// Stores object only if the chunk currently stored under the key has the
+    /// `expected` id, or if the key is absent when `expected` is `None`.
+    /// Otherwise, returns id of the current chunk as an inner error.
+    pub fn compare_and_swap(
+        &mut self,
+        table: impl ToString,
+        key: impl PrimaryKey,
+        expected: Option<ChunkId>,
+        data: &impl TryToChunk,
+    ) -> Result<Result<ChunkId, Option<ChunkId>>, ServerError<FailureCode>> {
+        let table = table.to_string();
+        let key = key.into_slice32();
+        trace!("Compare and swap object with id {}", key);
+        let chunk = data.try_to_chunk().map_err(|_| FailureCode::Encoding)?;
+        let request = Request::CompareAndSwap(CasReq {
+            table,
+            key,
+            expected,
+            chunk,
+        });
+        self.request(request)?.extract(|reply| match reply {
+            Reply::ChunkId(chunk_id) => Some(Ok(chunk_id)),
+            Reply::Mismatch(current) => Some(Err(current)),
+            _ => None,
+        })
     }
 
     pub fn retrieve<D>(
@@ -96,17 +229,23 @@ impl Client {
         let table = table.to_string();
         let key = key.into_slice32();
         trace!("Retrieve object with id {}", key);
-        let reply = self.request(Request::Retrieve(RetrieveReq { table, key }))?;
-        match reply {
-            Reply::Chunk(chunk) => D::try_from_chunk(chunk)
+        let chunk =
+            self.request(Request::Retrieve(RetrieveReq { table, key }))?.extract(|reply| {
+                match reply {
+                    Reply::Chunk(chunk) => Some(Some(chunk)),
+                    Reply::KeyAbsent(_) => Some(None),
+                    _ => None,
+                }
+            })?;
+        match chunk {
+            Some(chunk) => D::try_from_chunk(chunk)
                 .map_err(|_| FailureCode::Encoding)
                 .map_err(ServerError::from)
                 .map(Some),
-            Reply::KeyAbsent(_) => {
+            None => {
                 warn!("Object with id {} is not found", key);
                 Ok(None)
             }
-            _ => Err(ServerError::UnexpectedServerResponse),
         }
     }
 
@@ -118,6 +257,96 @@ impl Client {
         self.retrieve(table, key)
     }
 
+    pub fn store_batch<'data, K, D>(
+        &mut self,
+        table: impl ToString,
+        items: impl IntoIterator<Item = (K, &'data D)>,
+    ) -> Result<BTreeMap<Slice32, ChunkId>, ServerError<FailureCode>>
+    where
+        K: PrimaryKey,
+        D: TryToChunk + 'data,
+    {
+        let table = table.to_string();
+        let chunks = items
+            .into_iter()
+            .map(|(key, data)| data.try_to_chunk().map(|chunk| (key.into_slice32(), chunk)))
+            .collect::<Result<BTreeMap<_, _>, _>>()
+            .map_err(|_| FailureCode::Encoding)?;
+        trace!("Store batch of {} objects", chunks.len());
+        self.request(Request::StoreBatch(StoreBatchReq { table, chunks }))?.extract(|reply| {
+            match reply {
+                Reply::ChunkIds(chunk_ids) => Some(chunk_ids),
+                _ => None,
+            }
+        })
+    }
+
+    pub fn retrieve_batch<D>(
+        &mut self,
+        table: impl ToString,
+        keys: impl IntoIterator<Item = impl PrimaryKey>,
+    ) -> Result<BTreeMap<Slice32, Option<D>>, ServerError<FailureCode>>
+    where
+        D: TryFromChunk,
+    {
+        let table = table.to_string();
+        let keys = keys.into_iter().map(PrimaryKey::into_slice32).collect::<BTreeSet<_>>();
+        trace!("Retrieve batch of {} objects", keys.len());
+        let chunks = self
+            .request(Request::RetrieveBatch(RetrieveBatchReq { table, keys }))?
+            .extract(|reply| match reply {
+                Reply::Chunks(chunks) => Some(chunks),
+                _ => None,
+            })?;
+        chunks
+            .into_iter()
+            .map(|(key, chunk)| {
+                chunk
+                    .map(D::try_from_chunk)
+                    .transpose()
+                    .map(|data| (key, data))
+                    .map_err(|_| FailureCode::Encoding)
+                    .map_err(ServerError::from)
+            })
+            .collect()
+    }
+
+    /// Performs operations over multiple tables as a single atomic transaction,
+    /// returning results of the operations in their order. If any of the
+    /// operations fails, none of them is applied and the failure is returned.
+    pub fn transaction(
+        &mut self,
+        ops: impl IntoIterator<Item = TxOp>,
+    ) -> Result<Vec<TxResult>, ServerError<FailureCode>> {
+        let ops = ops.into_iter().collect::<Vec<_>>();
+        trace!("Perform transaction of {} operation(s)", ops.len());
+        self.request(Request::Transaction(TransactionReq { ops }))?.extract(|reply| match reply {
+            Reply::TxResults(results) => Some(results),
+            _ => None,
+        })
+    }
+
+    pub fn remove(
+        &mut self,
+        table: impl ToString,
+        key: impl PrimaryKey,
+    ) -> Result<Option<ChunkId>, ServerError<FailureCode>> {
+        let table = table.to_string();
+        let key = key.into_slice32();
+        trace!("Remove object with id {}", key);
+        let removed = self.request(Request::Delete(RetrieveReq { table, key }))?.extract(
+            |reply| match reply {
+                Reply::ChunkId(chunk_id) => Some(Some(chunk_id)),
+                Reply::KeyAbsent(_) => Some(None),
+                _ => None,
+            },
+        )?;
+        if removed.is_none() {
+            warn!("Object with id {} is not found", key);
+        }
+        Ok(removed)
+    }
+
     pub fn insert_into_set(
         &mut self,
         table: impl ToString,
@@ -127,41 +356,148 @@ impl Client {
         let table = table.to_string();
         let key = key.into_slice32();
         let item = item.into();
-        let reply = self.request(Request::Insert(InsertReq { table, key, item }))?;
-        match reply {
-            Reply::Success => Ok(()),
-            Reply::Failure(failure) => {
-                warn!("Failure storing object with id {}", key);
-                Err(failure.into())
+        self.request(Request::Insert(InsertReq { table, key, item }))?.success_or_failure()
+    }
+
+    pub fn set_members(
+        &mut self,
+        table: impl ToString,
+        key: impl PrimaryKey,
+    ) -> Result<BTreeSet<Slice32>, ServerError<FailureCode>> {
+        let table = table.to_string();
+        let key = key.into_slice32();
+        self.request(Request::Members(RetrieveReq { table, key }))?.extract(|reply| match reply {
+            Reply::Members(members) => Some(members),
+            _ => None,
+        })
+    }
+
+    /// Removes item from the set stored under the key. Returns whether the item
+    /// was a member of the set.
+    pub fn remove_from_set(
+        &mut self,
+        table: impl ToString,
+        key: impl PrimaryKey,
+        item: impl Into<Slice32>,
+    ) -> Result<bool, ServerError<FailureCode>> {
+        let table = table.to_string();
+        let key = key.into_slice32();
+        let item = item.into();
+        self.request(Request::RemoveMember(InsertReq { table, key, item }))?.extract(|reply| {
+            match reply {
+                Reply::Membership(was_member) => Some(was_member),
+                _ => None,
             }
-            _ => Err(ServerError::UnexpectedServerResponse),
-        }
+        })
     }
 
+    pub fn set_contains(
+        &mut self,
+        table: impl ToString,
+        key: impl PrimaryKey,
+        item: impl Into<Slice32>,
+    ) -> Result<bool, ServerError<FailureCode>> {
+        let table = table.to_string();
+        let key = key.into_slice32();
+        let item = item.into();
+        self.request(Request::IsMember(InsertReq { table, key, item }))?.extract(
+            |reply| match reply {
+                Reply::Membership(is_member) => Some(is_member),
+                _ => None,
+            },
+        )
+    }
+
+    pub fn set_len(
+        &mut self,
+        table: impl ToString,
+        key: impl PrimaryKey,
+    ) -> Result<u64, ServerError<FailureCode>> {
+        let table = table.to_string();
+        let key = key.into_slice32();
+        self.request(Request::MemberCount(RetrieveReq { table, key }))?.extract(|reply| match reply
+        {
+            Reply::Count(count) => Some(count),
+            _ => None,
+        })
+    }
+
+    /// Lists all ids stored in the table with a single request. For large
+    /// tables prefer [`Client::ids_iter`], which is supported by newer servers
+    /// only.
     pub fn ids(
         &mut self,
         table: impl ToString,
     ) -> Result<BTreeSet<ChunkId>, ServerError<FailureCode>> {
-        let reply = self.request(Request::ListIds(table.to_string()))?;
-        match reply {
-            Reply::Ids(ids) => Ok(ids),
-            _ => Err(ServerError::UnexpectedServerResponse),
+        self.request(Request::ListIds(table.to_string()))?.extract(|reply| match reply {
+            Reply::Ids(ids) => Some(ids),
+            _ => None,
+        })
+    }
+
+    /// Returns iterator over all ids stored in the table, which requests them
+    /// from the server page by page, each page containing up to `page_size`
+    /// ids.
+    pub fn ids_iter(&mut self, table: impl ToString, page_size: u32) -> IdsIter<'_> {
+        IdsIter {
+            client: self,
+            table: table.to_string(),
+            page_size: page_size.max(1),
+            page: BTreeSet::new().into_iter(),
+            next: None,
+            done: false,
         }
     }
 
+    pub fn ids_page(
+        &mut self,
+        table: impl ToString,
+        after: Option<ChunkId>,
+        until: Option<ChunkId>,
+        limit: u32,
+    ) -> Result<IdsPage, ServerError<FailureCode>> {
+        self.request(Request::ListIdsPage(ListIdsReq {
+            table: table.to_string(),
+            after,
+            until,
+            limit,
+        }))?
+        .extract(|reply| match reply {
+            Reply::IdsPage(page) => Some(page),
+            _ => None,
+        })
+    }
+
     pub fn filter_unknown(
         &mut self,
         table: impl ToString,
         ids: BTreeSet<ChunkId>,
     ) -> Result<BTreeSet<ChunkId>, ServerError<FailureCode>> {
-        let reply = self.request(Request::CheckUnknown(CheckUnknownReq {
+        self.request(Request::CheckUnknown(CheckUnknownReq {
             table: table.to_string(),
             ids,
-        }))?;
-        match reply {
-            Reply::Ids(ids) => Ok(ids),
-            _ => Err(ServerError::UnexpectedServerResponse),
-        }
+        }))?
+        .extract(|reply| match reply {
+            Reply::Ids(ids) => Some(ids),
+            _ => None,
+        })
+    }
+
+    /// Splits the provided ids into the ones which are known to the table and
+    /// the ones which are absent from it.
+    pub fn partition_ids(
+        &mut self,
+        table: impl ToString,
+        ids: BTreeSet<ChunkId>,
+    ) -> Result<IdsPartition, ServerError<FailureCode>> {
+        self.request(Request::PartitionIds(CheckUnknownReq {
+            table: table.to_string(),
+            ids,
+        }))?
+        .extract(|reply| match reply {
+            Reply::IdsPartition(partition) => Some(partition),
+            _ => None,
+        })
     }
 
     fn request(&mut self, request: Request) -> Result<Reply, ServerError<FailureCode>> {
@@ -177,3 +513,40 @@ impl Client {
         Ok((*reply).clone())
     }
 }
+
+/// Iterator over ids stored in a table, which fetches them from the server page
+/// by page. Constructed with [`Client::ids_iter`].
+pub struct IdsIter<'client> {
+    client: &'client mut Client,
+    table: String,
+    page_size: u32,
+    page: btree_set::IntoIter<ChunkId>,
+    next: Option<ChunkId>,
+    done: bool,
+}
+
+impl<'client> Iterator for IdsIter<'client> {
+    type Item = Result<ChunkId, ServerError<FailureCode>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(id) = self.page.next() {
+                return Some(Ok(id));
+            }
+            if self.done {
+                return None;
+            }
+            match self.client.ids_page(&self.table, self.next, None, self.page_size) {
+                Ok(IdsPage { ids, next }) => {
+                    self.done = next.is_none();
+                    self.next = next;
+                    self.page = ids.into_iter();
+                }
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+            }
+        }
+    }
+}
@@ -24,6 +24,42 @@ pub enum FailureCode {
 
     /// internal encoding erorr
     Encoding = 0x02,
+
+    /// permission denied
+    PermissionDenied = 0x03,
+
+    /// unknown database table
+    UnknownTable = 0x04,
+
+    /// key is absent from the table
+    KeyAbsent = 0x05,
+
+    /// stored value has a type different from the requested one
+    ValueTypeMismatch = 0x06,
+
+    /// storage quota exceeded
+    QuotaExceeded = 0x07,
+
+    /// storage is read-only
+    ReadOnly = 0x08,
+
+    /// operation is not supported by the storage backend
+    Unsupported = 0x09,
+
+    /// key does not match id of the chunk
+    IdMismatch = 0x0A,
+
+    /// stored data are corrupted
+    Corrupted = 0x0B,
+
+    /// database table already exists
+    TableExists = 0x0C,
+
+    /// daemon is shutting down
+    ShuttingDown = 0x0D,
+
+    /// daemon is unable to initiate shutdown
+    ShutdownFailed = 0x0E,
 }
 
 impl From<u16> for FailureCode {
@@ -31,6 +67,18 @@ impl From<u16> for FailureCode {
         match value {
             x if x == FailureCode::Database as u16 => FailureCode::Database,
             x if x == FailureCode::Encoding as u16 => FailureCode::Encoding,
+            x if x == FailureCode::PermissionDenied as u16 => FailureCode::PermissionDenied,
+            x if x == FailureCode::UnknownTable as u16 => FailureCode::UnknownTable,
+            x if x == FailureCode::KeyAbsent as u16 => FailureCode::KeyAbsent,
+            x if x == FailureCode::ValueTypeMismatch as u16 => FailureCode::ValueTypeMismatch,
+            x if x == FailureCode::QuotaExceeded as u16 => FailureCode::QuotaExceeded,
+            x if x == FailureCode::ReadOnly as u16 => FailureCode::ReadOnly,
+            x if x == FailureCode::Unsupported as u16 => FailureCode::Unsupported,
+            x if x == FailureCode::IdMismatch as u16 => FailureCode::IdMismatch,
+            x if x == FailureCode::Corrupted as u16 => FailureCode::Corrupted,
+            x if x == FailureCode::TableExists as u16 => FailureCode::TableExists,
+            x if x == FailureCode::ShuttingDown as u16 => FailureCode::ShuttingDown,
+            x if x == FailureCode::ShutdownFailed as u16 => FailureCode::ShutdownFailed,
             _ => FailureCode::Unknown,
         }
     }
@@ -58,3 +106,55 @@ impl From<FailureCode> for ServerError<FailureCode> {
 }
 
 impl rpc::FailureCodeExt for FailureCode {}
+
+/// Access to the failure code reported by the server, allowing callers to
+/// branch on the failure reason.
+pub trait ServerErrorExt {
+    /// Returns failure code if the error is a failure reported by the daemon;
+    /// `None` for transport, encoding and other client-side errors.
+    fn failure_code(&self) -> Option<FailureCode>;
+}
+
+impl ServerErrorExt for ServerError<FailureCode> {
+    fn failure_code(&self) -> Option<FailureCode> {
+        match self {
+            ServerError::ServerFailure(rpc::Failure {
+                code: rpc::FailureCode::Other(code),
+                ..
+            }) => Some(*code),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn failure_code_roundtrip() {
+        for code in [
+            FailureCode::Unknown,
+            FailureCode::Database,
+            FailureCode::Encoding,
+            FailureCode::PermissionDenied,
+            FailureCode::UnknownTable,
+            FailureCode::KeyAbsent,
+            FailureCode::ValueTypeMismatch,
+            FailureCode::QuotaExceeded,
+            FailureCode::ReadOnly,
+            FailureCode::Unsupported,
+            FailureCode::IdMismatch,
+            FailureCode::Corrupted,
+            FailureCode::TableExists,
+            FailureCode::ShuttingDown,
+            FailureCode::ShutdownFailed,
+        ] {
+            assert_eq!(FailureCode::from(u16::from(code)), code);
+            let rpc_code = rpc::FailureCode::from(u16::from(rpc::FailureCode::from(code)));
+            assert_eq!(rpc_code, rpc::FailureCode::Other(code));
+            assert_eq!(ServerError::from(code).failure_code(), Some(code));
+        }
+        assert_eq!(FailureCode::from(0x0AAA), FailureCode::Unknown);
+    }
+}
@@ -0,0 +1,102 @@
+// Storage daemon (stored): microservice frontend for different storage backends
+// used in LNP/BP nodes.
+//
+// Written in 2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// Copyright (C) 2022 by LNP/BP Standards Association, Switzerland.
+//
+// You should have received a copy of the MIT License along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Notifications about changes in the storage, published by the daemon over
+//! ZMQ PUB socket.
+//!
+//! Each notification is sent as a two-frame message: the first frame contains
+//! name of the table, such that subscribers may filter notifications by table
+//! name prefix, and the second one is the strict-encoded [`Event`].
+
+use amplify::Slice32;
+use internet2::addr::ServiceAddr;
+use microservices::rpc::ServerError;
+use microservices::ZMQ_CONTEXT;
+use storm::ChunkId;
+use strict_encoding::{StrictDecode, StrictEncode};
+
+use crate::FailureCode;
+
+/// Operation which has changed data in a table
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug, Display)]
+#[derive(StrictEncode, StrictDecode)]
+#[strict_encoding(by_value, repr = u8)]
+#[display(lowercase)]
+#[repr(u8)]
+pub enum Operation {
+    /// Chunk was stored under the key
+    Store = 0x10,
+
+    /// Item was added to the set stored under the key
+    Insert = 0x14,
+
+    /// Chunk stored under the key was removed
+    Delete = 0x1a,
+}
+
+/// Notification about a change in the storage.
+#[derive(Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug, Display)]
+#[derive(NetworkEncode, NetworkDecode)]
+#[display("{operation} {table} {key} {chunk_id}")]
+pub struct Event {
+    pub table: String,
+    pub key: Slice32,
+    /// Id of the stored or removed chunk; for [`Operation::Insert`] this is
+    /// the item added to the set.
+    pub chunk_id: ChunkId,
+    pub operation: Operation,
+}
+
+impl Event {
+    /// Serializes event into ZMQ message frames.
+    pub fn to_frames(&self) -> Result<[Vec<u8>; 2], strict_encoding::Error> {
+        Ok([self.table.as_bytes().to_vec(), self.strict_serialize()?])
+    }
+}
+
+/// Client receiving change notifications published by the daemon.
+pub struct Subscriber {
+    socket: zmq::Socket,
+}
+
+impl Subscriber {
+    /// Connects to the daemon notification endpoint, receiving notifications
+    /// for tables which names start with `prefix`. Empty prefix subscribes to
+    /// all the tables.
+    pub fn with(connect: &ServiceAddr, prefix: &str) -> Result<Self, ServerError<FailureCode>> {
+        trace!("Subscribing to store daemon notifications at {}", connect);
+        let socket = ZMQ_CONTEXT.socket(zmq::SUB)?;
+        socket.connect(&connect.zmq_connect_string())?;
+        socket.set_subscribe(prefix.as_bytes())?;
+        Ok(Self { socket })
+    }
+
+    /// Adds subscription to one more table name prefix.
+    pub fn subscribe(&mut self, prefix: &str) -> Result<(), ServerError<FailureCode>> {
+        self.socket.set_subscribe(prefix.as_bytes())?;
+        Ok(())
+    }
+
+    /// Blocks until the next notification arrives.
+    pub fn recv(&mut self) -> Result<Event, ServerError<FailureCode>> {
+        let frames = self.socket.recv_multipart(0)?;
+        match frames.as_slice() {
+            [_, data] => Ok(Event::strict_deserialize(data)?),
+            _ => Err(ServerError::UnexpectedServerResponse),
+        }
+    }
+}
+
+impl Iterator for Subscriber {
+    type Item = Result<Event, ServerError<FailureCode>>;
+
+    fn next(&mut self) -> Option<Self::Item> { Some(self.recv()) }
+}
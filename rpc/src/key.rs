@@ -0,0 +1,48 @@
+// Storage daemon (stored): microservice frontend for different storage backends
+// used in LNP/BP nodes.
+//
+// Written in 2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// Copyright (C) 2022 by LNP/BP Standards Association, Switzerland.
+//
+// You should have received a copy of the MIT License along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use amplify::hex::ToHex;
+use internet2::addr::LocalNode;
+use secp256k1::{Secp256k1, SecretKey};
+
+/// Reads node secret key from a file, containing it in hex form. If the file
+/// does not exist, generates a new key and saves it into the file, readable
+/// only by the current user.
+pub fn read_or_create_key(path: &Path) -> Result<LocalNode, io::Error> {
+    let secp = Secp256k1::new();
+    match fs::read_to_string(path) {
+        Ok(hex) => {
+            let key = SecretKey::from_str(hex.trim())
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            Ok(LocalNode::with(&secp, key))
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            let node = LocalNode::new(&secp);
+            debug!("Saving new node key {} to {}", node.node_id(), path.display());
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            let mut options = fs::OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            let mut file = options.open(path)?;
+            writeln!(file, "{}", node.private_key().secret_bytes().to_hex())?;
+            Ok(node)
+        }
+        Err(err) => Err(err),
+    }
+}
@@ -28,19 +28,30 @@ extern crate serde_crate as serde;
 
 pub mod client;
 mod error;
+mod event;
+mod key;
 mod reply;
 mod request;
 
 use std::borrow::Borrow;
 
 use amplify::Slice32;
-pub use client::Client;
-pub use error::FailureCode;
-pub use reply::Reply;
-pub use request::{CheckUnknownReq, InsertReq, Request, RetrieveReq, StoreReq};
+pub use client::{Client, IdsIter};
+pub use error::{FailureCode, ServerErrorExt};
+pub use event::{Event, Operation, Subscriber};
+pub use key::read_or_create_key;
+pub use reply::{BadEntry, Defect, IdsPage, IdsPartition, Reply, TxResult, VerifyReport};
+pub use request::{
+    BackupReq, CasReq, CheckUnknownReq, InsertReq, ListIdsReq, RenameTableReq, Request,
+    RetrieveBatchReq, RetrieveReq, StoreBatchReq, StoreContentReq, StoreReq, TransactionReq, TxOp,
+    VerifyReq,
+};
 
 pub const STORED_RPC_ENDPOINT: &str = "0.0.0.0:60960";
 
+/// Maximal number of ids returned by the server in a single page.
+pub const LIST_IDS_PAGE_LIMIT: u32 = 0x4000;
+
 pub trait PrimaryKey: Copy {
     fn into_array(self) -> [u8; 32];
     fn into_slice32(self) -> Slice32 { Slice32::from(self.into_array()) }
@@ -79,7 +90,7 @@ mod test {
         fn take_primary_key(_: impl PrimaryKey) {}
 
         take_primary_key(Slice32::default());
-        take_primary_key(sha256::Hash::default());
+        take_primary_key(sha256::Hash::all_zeros());
         take_primary_key(Id::default());
     }
 }
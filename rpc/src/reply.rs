@@ -11,8 +11,10 @@
 
 #![allow(clippy::clone_on_copy)] // Caused by Api derivation on Reply type
 
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Display, Formatter};
 
+use amplify::hex::ToHex;
 use amplify::Slice32;
 use internet2::presentation;
 use microservices::rpc;
@@ -57,13 +59,139 @@ pub enum Reply {
     #[display("ids(...)")]
     Ids(BTreeSet<ChunkId>),
 
+    #[api(type = 0x0016)]
+    #[display("ids_page({0})")]
+    IdsPage(IdsPage),
+
+    #[api(type = 0x0017)]
+    #[display("ids_partition({0})")]
+    IdsPartition(IdsPartition),
+
+    #[api(type = 0x0030)]
+    #[display("members(...)")]
+    Members(BTreeSet<Slice32>),
+
+    #[api(type = 0x0031)]
+    #[display("membership({0})")]
+    Membership(bool),
+
     #[api(type = 0x0012)]
     #[display("key_absent({0})")]
     KeyAbsent(Slice32),
+
+    /// Conditional store was not performed since the key holds a chunk other
+    /// than the expected one. Contains id of the current chunk, or `None` if
+    /// the key is absent.
+    #[api(type = 0x0019)]
+    #[display("mismatch({0:?})")]
+    Mismatch(Option<ChunkId>),
+
+    /// Report on integrity of the table entries.
+    #[api(type = 0x001a)]
+    #[display("verified({0})")]
+    Verified(VerifyReport),
+
+    #[api(type = 0x0014)]
+    #[display("chunk_ids(...)")]
+    ChunkIds(BTreeMap<Slice32, ChunkId>),
+
+    #[api(type = 0x0015)]
+    #[display("chunks(...)")]
+    Chunks(BTreeMap<Slice32, Option<Chunk>>),
+
+    /// Results of the transaction operations, in the order of the operations.
+    #[api(type = 0x0018)]
+    #[display("tx_results(...)")]
+    TxResults(Vec<TxResult>),
 }
 
 impl rpc::Reply for Reply {}
 
+#[derive(Clone, Eq, PartialEq, Hash, Debug, Display)]
+#[derive(NetworkEncode, NetworkDecode)]
+#[display("..., {next:?}")]
+pub struct IdsPage {
+    pub ids: BTreeSet<ChunkId>,
+    /// Cursor for requesting the next page; `None` if there are no more ids
+    /// in the requested range.
+    pub next: Option<ChunkId>,
+}
+
+#[derive(Clone, Eq, PartialEq, Hash, Debug, Default, Display)]
+#[derive(NetworkEncode, NetworkDecode)]
+#[display("...")]
+pub struct IdsPartition {
+    /// Ids which are present in the table
+    pub known: BTreeSet<ChunkId>,
+    /// Ids which are absent from the table
+    pub unknown: BTreeSet<ChunkId>,
+}
+
+/// Report on integrity verification of a table.
+#[derive(Clone, Eq, PartialEq, Hash, Debug, Default, Display)]
+#[derive(NetworkEncode, NetworkDecode)]
+#[display("{checked} checked, ...")]
+pub struct VerifyReport {
+    /// Number of checked entries
+    pub checked: u64,
+    /// Entries which have failed verification
+    pub bad: Vec<BadEntry>,
+}
+
+/// Table entry which has failed integrity verification.
+#[derive(Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
+#[derive(NetworkEncode, NetworkDecode)]
+pub struct BadEntry {
+    /// Key of the entry, which may be of non-standard length
+    pub key: Vec<u8>,
+    pub defect: Defect,
+}
+
+impl Display for BadEntry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.key.to_hex(), self.defect)
+    }
+}
+
+/// Defect of a table entry found by integrity verification.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug, Display)]
+#[derive(StrictEncode, StrictDecode)]
+#[strict_encoding(by_value, repr = u8)]
+#[display(doc_comments)]
+#[repr(u8)]
+pub enum Defect {
+    /// key is not 32 bytes long
+    KeyLength = 0x01,
+
+    /// value is neither a chunk nor a set of keys
+    ValueType = 0x02,
+
+    /// chunk in a content-addressed table is stored under a key other than
+    /// its id
+    IdMismatch = 0x03,
+}
+
+/// Result of a single operation inside a transaction.
+#[derive(Clone, Eq, PartialEq, Hash, Debug, Display)]
+#[derive(NetworkEncode, NetworkDecode)]
+pub enum TxResult {
+    /// Chunk was stored under the returned id
+    #[display("stored({0})")]
+    Stored(ChunkId),
+
+    /// Item was inserted into the set
+    #[display("inserted")]
+    Inserted,
+
+    /// Chunk with the returned id was deleted; `None` if the key was absent
+    #[display("deleted({0:?})")]
+    Deleted(Option<ChunkId>),
+
+    /// Item was removed from the set; `false` if it was not a member
+    #[display("removed({0})")]
+    Removed(bool),
+}
+
 impl From<presentation::Error> for Reply {
     fn from(err: presentation::Error) -> Self {
         Reply::Failure(rpc::Failure {
@@ -74,11 +202,58 @@ impl From<presentation::Error> for Reply {
 }
 
 impl Reply {
-    pub fn success_or_failure(self) -> Result<(), ServerError<FailureCode>> {
+    /// Extracts typed value from the reply using `extractor`, which returns
+    /// `None` for replies not expected by the caller. Server failures are
+    /// returned as [`ServerError::ServerFailure`] with the original code and
+    /// information; all other unexpected replies result in
+    /// [`ServerError::UnexpectedServerResponse`].
+    pub fn extract<T>(
+        self,
+        extractor: impl FnOnce(Reply) -> Option<T>,
+    ) -> Result<T, ServerError<FailureCode>> {
         match self {
-            Reply::Success => Ok(()),
-            Reply::Failure(failure) => Err(failure.into()),
-            _ => Err(ServerError::UnexpectedServerResponse),
+            Reply::Failure(failure) => {
+                debug!("Server reported {}", failure);
+                Err(failure.into())
+            }
+            reply => extractor(reply).ok_or(ServerError::UnexpectedServerResponse),
+        }
+    }
+
+    pub fn success_or_failure(self) -> Result<(), ServerError<FailureCode>> {
+        self.extract(|reply| match reply {
+            Reply::Success => Some(()),
+            _ => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ServerErrorExt;
+
+    #[test]
+    fn reply_extraction() {
+        let count = |reply| match reply {
+            Reply::Count(count) => Some(count),
+            _ => None,
+        };
+        assert_eq!(Reply::Count(5).extract(count).unwrap(), 5);
+        assert!(matches!(
+            Reply::Success.extract(count),
+            Err(ServerError::UnexpectedServerResponse)
+        ));
+        let failure = rpc::Failure {
+            code: FailureCode::UnknownTable.into(),
+            info: s!("unknown database table 'x'"),
+        };
+        match Reply::Failure(failure.clone()).extract(count) {
+            Err(err @ ServerError::ServerFailure(_)) => {
+                assert_eq!(err.failure_code(), Some(FailureCode::UnknownTable));
+                assert!(matches!(err, ServerError::ServerFailure(f) if f == failure));
+            }
+            _ => panic!("server failure is not preserved"),
         }
     }
 }
@@ -9,7 +9,7 @@
 // You should have received a copy of the MIT License along with this software.
 // If not, see <https://opensource.org/licenses/MIT>.
 
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 
 use amplify::Slice32;
 use storm::{Chunk, ChunkId};
@@ -23,6 +23,8 @@ pub enum Request {
     #[display("use({0})")]
     Use(String),
 
+    /// Lists tables which are used or exist in the storage. Requires read
+    /// permission for all tables (`*`).
     #[api(type = 0xa1)]
     #[display("tables({0})")]
     Tables,
@@ -31,10 +33,71 @@ pub enum Request {
     #[display("count({0})")]
     Count(String),
 
+    /// Removes table together with all its data from the storage. Not
+    /// supported by content-addressed tables.
+    #[api(type = 0xa4)]
+    #[display("drop_table({0})")]
+    DropTable(String),
+
+    /// Removes all data from the table, keeping the table itself.
+    #[api(type = 0xa5)]
+    #[display("clear_table({0})")]
+    ClearTable(String),
+
+    /// Renames table; fails if a table with the new name already exists.
+    /// Content-addressed tables can't be renamed, nor can other tables take
+    /// their names.
+    #[api(type = 0xa6)]
+    #[display("rename_table({0})")]
+    RenameTable(RenameTableReq),
+
+    /// Stops the daemon after completing requests in flight and flushing all
+    /// data to disk. Requires admin permission for all tables (`*`).
+    #[api(type = 0xa7)]
+    #[display("shutdown")]
+    Shutdown,
+
+    /// Flushes all data written so far to disk, independently of the
+    /// durability mode used by the daemon. Requires write permission for all
+    /// tables (`*`).
+    #[api(type = 0xa8)]
+    #[display("flush")]
+    Flush,
+
+    /// Writes snapshot of all tables into a backup archive on the daemon host.
+    /// Each table is consistent on its own; other requests are processed while
+    /// the archive is written. Requires admin permission for all tables (`*`).
+    #[api(type = 0xaa)]
+    #[display("backup({0})")]
+    Backup(BackupReq),
+
+    /// Checks integrity of all entries in the table, reporting the ones which
+    /// are corrupted. Moving corrupted entries into the quarantine table
+    /// requires admin permission on the checked table.
+    #[api(type = 0xa9)]
+    #[display("verify({0})")]
+    Verify(VerifyReq),
+
     #[api(type = 0x10)]
     #[display("store({0})")]
     Store(StoreReq),
 
+    /// Stores chunk into a content-addressed table under its id.
+    #[api(type = 0x28)]
+    #[display("store_content({0})")]
+    StoreContent(StoreContentReq),
+
+    /// Stores chunk only if there is no value under the key.
+    #[api(type = 0x1e)]
+    #[display("store_if_absent({0})")]
+    StoreIfAbsent(StoreReq),
+
+    /// Stores chunk only if the chunk currently stored under the key has the
+    /// expected id.
+    #[api(type = 0x26)]
+    #[display("compare_and_swap({0})")]
+    CompareAndSwap(CasReq),
+
     #[api(type = 0x12)]
     #[display("retrieve({0})")]
     Retrieve(RetrieveReq),
@@ -47,9 +110,131 @@ pub enum Request {
     #[display("list_ids({0})")]
     ListIds(String),
 
+    /// Lists ids stored in a table page by page, in ascending order.
+    #[api(type = 0x1c)]
+    #[display("list_ids_page({0})")]
+    ListIdsPage(ListIdsReq),
+
     #[api(type = 0x18)]
     #[display("check_unknown({0})")]
     CheckUnknown(CheckUnknownReq),
+
+    /// Splits the provided ids into the ones present in the table and the ones
+    /// absent from it.
+    #[api(type = 0x2a)]
+    #[display("partition_ids({0})")]
+    PartitionIds(CheckUnknownReq),
+
+    /// Removes value stored under the key. Replies with the id of the removed
+    /// chunk, or with key absence if there was no such key.
+    #[api(type = 0x1a)]
+    #[display("delete({0})")]
+    Delete(RetrieveReq),
+
+    /// Returns all members of the set stored under the key.
+    #[api(type = 0x30)]
+    #[display("members({0})")]
+    Members(RetrieveReq),
+
+    /// Removes item from the set stored under the key.
+    #[api(type = 0x32)]
+    #[display("remove_member({0})")]
+    RemoveMember(InsertReq),
+
+    /// Checks whether the item is a member of the set stored under the key.
+    #[api(type = 0x34)]
+    #[display("is_member({0})")]
+    IsMember(InsertReq),
+
+    /// Counts members of the set stored under the key.
+    #[api(type = 0x36)]
+    #[display("member_count({0})")]
+    MemberCount(RetrieveReq),
+
+    /// Stores multiple chunks into a table as a single atomic operation.
+    #[api(type = 0x20)]
+    #[display("store_batch({0})")]
+    StoreBatch(StoreBatchReq),
+
+    /// Retrieves multiple chunks from a table at once.
+    #[api(type = 0x22)]
+    #[display("retrieve_batch({0})")]
+    RetrieveBatch(RetrieveBatchReq),
+
+    /// Performs operations over multiple tables as a single atomic
+    /// transaction: either all of them succeed, or none is applied.
+    #[api(type = 0x24)]
+    #[display("transaction({0})")]
+    Transaction(TransactionReq),
+}
+
+/// Operation which can be performed as a part of a transaction.
+#[derive(Clone, Ord, PartialOrd, PartialEq, Eq, Debug, Hash, Display)]
+#[derive(NetworkEncode, NetworkDecode)]
+pub enum TxOp {
+    /// Stores chunk under the key, like [`Request::Store`].
+    #[display("store({0})")]
+    Store(StoreReq),
+
+    /// Inserts item into the set stored under the key, like
+    /// [`Request::Insert`].
+    #[display("insert({0})")]
+    Insert(InsertReq),
+
+    /// Removes chunk stored under the key, like [`Request::Delete`].
+    #[display("delete({0})")]
+    Delete(RetrieveReq),
+
+    /// Removes item from the set stored under the key, like
+    /// [`Request::RemoveMember`].
+    #[display("remove_member({0})")]
+    RemoveMember(InsertReq),
+}
+
+impl TxOp {
+    /// Returns name of the table affected by the operation.
+    pub fn table(&self) -> &str {
+        match self {
+            TxOp::Store(StoreReq { table, .. })
+            | TxOp::Insert(InsertReq { table, .. })
+            | TxOp::Delete(RetrieveReq { table, .. })
+            | TxOp::RemoveMember(InsertReq { table, .. }) => table,
+        }
+    }
+}
+
+#[derive(Clone, Ord, PartialOrd, PartialEq, Eq, Debug, Hash, Display)]
+#[derive(NetworkEncode, NetworkDecode)]
+#[display("...")]
+pub struct TransactionReq {
+    pub ops: Vec<TxOp>,
+}
+
+#[derive(Clone, Ord, PartialOrd, PartialEq, Eq, Debug, Hash, Display)]
+#[derive(NetworkEncode, NetworkDecode)]
+#[display("{from} -> {to}")]
+pub struct RenameTableReq {
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Clone, Ord, PartialOrd, PartialEq, Eq, Debug, Hash, Display)]
+#[derive(NetworkEncode, NetworkDecode)]
+#[display("{table}, quarantine: {quarantine}")]
+pub struct VerifyReq {
+    pub table: String,
+    /// Whether corrupted entries must be moved into the `_corrupt` table.
+    pub quarantine: bool,
+}
+
+#[derive(Clone, Ord, PartialOrd, PartialEq, Eq, Debug, Hash, Display)]
+#[derive(NetworkEncode, NetworkDecode)]
+#[display("{path}")]
+pub struct BackupReq {
+    /// Path to the archive file relative to the `backups` directory inside
+    /// the daemon data directory. Absolute paths and `..` components are
+    /// rejected. Existing file is overwritten.
+    pub path: String,
 }
 
 #[derive(Clone, Ord, PartialOrd, PartialEq, Eq, Debug, Hash, Display)]
@@ -61,6 +246,34 @@ pub struct StoreReq {
     pub chunk: Chunk,
 }
 
+#[derive(Clone, Ord, PartialOrd, PartialEq, Eq, Debug, Hash, Display)]
+#[derive(NetworkEncode, NetworkDecode)]
+#[display("{table}, ...")]
+pub struct StoreContentReq {
+    pub table: String,
+    pub chunk: Chunk,
+}
+
+#[derive(Clone, Ord, PartialOrd, PartialEq, Eq, Debug, Hash, Display)]
+#[derive(NetworkEncode, NetworkDecode)]
+#[display("{table}, {key}, {expected:?}, ...")]
+pub struct CasReq {
+    pub table: String,
+    pub key: Slice32,
+    /// Id of the chunk expected to be currently stored under the key; `None`
+    /// if the key is expected to be absent.
+    pub expected: Option<ChunkId>,
+    pub chunk: Chunk,
+}
+
+#[derive(Clone, Ord, PartialOrd, PartialEq, Eq, Debug, Hash, Display)]
+#[derive(NetworkEncode, NetworkDecode)]
+#[display("{table}, ...")]
+pub struct StoreBatchReq {
+    pub table: String,
+    pub chunks: BTreeMap<Slice32, Chunk>,
+}
+
 #[derive(Clone, Ord, PartialOrd, PartialEq, Eq, Debug, Hash, Display)]
 #[derive(NetworkEncode, NetworkDecode)]
 #[display("{table}, {key}")]
@@ -69,6 +282,14 @@ pub struct RetrieveReq {
     pub key: Slice32,
 }
 
+#[derive(Clone, Ord, PartialOrd, PartialEq, Eq, Debug, Hash, Display)]
+#[derive(NetworkEncode, NetworkDecode)]
+#[display("{table}, ...")]
+pub struct RetrieveBatchReq {
+    pub table: String,
+    pub keys: BTreeSet<Slice32>,
+}
+
 #[derive(Clone, Ord, PartialOrd, PartialEq, Eq, Debug, Hash, Display)]
 #[derive(NetworkEncode, NetworkDecode)]
 #[display("{table}, {key}, ...")]
@@ -78,6 +299,23 @@ pub struct InsertReq {
     pub item: Slice32,
 }
 
+#[derive(Clone, Ord, PartialOrd, PartialEq, Eq, Debug, Hash, Display)]
+#[derive(NetworkEncode, NetworkDecode)]
+#[display("{table}, {limit}, ...")]
+pub struct ListIdsReq {
+    pub table: String,
+    /// Cursor returned with the previous page; only ids greater than it are
+    /// listed. If `None`, the listing starts from the first id.
+    pub after: Option<ChunkId>,
+    /// Exclusive upper bound of the listing. If `None`, the listing continues
+    /// up to the last id.
+    pub until: Option<ChunkId>,
+    /// Maximal number of ids to return. The server may return fewer ids if the
+    /// limit exceeds [`crate::LIST_IDS_PAGE_LIMIT`]; zero limit stands for
+    /// [`crate::LIST_IDS_PAGE_LIMIT`].
+    pub limit: u32,
+}
+
 #[derive(Clone, Ord, PartialOrd, PartialEq, Eq, Debug, Hash, Display)]
 #[derive(NetworkEncode, NetworkDecode)]
 #[display("{table}, ...")]
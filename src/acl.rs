@@ -0,0 +1,176 @@
+// Storage daemon (stored): microservice frontend for different storage backends
+// used in LNP/BP nodes.
+//
+// Written in 2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// Copyright (C) 2022 by LNP/BP Standards Association, Switzerland.
+//
+// You should have received a copy of the MIT License along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+use std::collections::BTreeSet;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+use internet2::addr::NodeId;
+
+/// Operations on a table which may be granted to a client
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug, Display)]
+#[display(lowercase)]
+pub enum Permission {
+    /// Reading data and listing ids
+    Read,
+
+    /// Storing and removing data
+    Write,
+
+    /// Creating new tables
+    Create,
+
+    /// Dropping, clearing and renaming tables; implies all other permissions
+    Admin,
+}
+
+impl FromStr for Permission {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "read" => Ok(Permission::Read),
+            "write" => Ok(Permission::Write),
+            "create" => Ok(Permission::Create),
+            "admin" => Ok(Permission::Admin),
+            other => Err(format!("unknown permission '{}'", other)),
+        }
+    }
+}
+
+/// Rule granting permissions on tables to a client.
+///
+/// Rules are written as `<client>:<table>:<permissions>`, where `client` is
+/// the client public key or `*` for any client (including clients connected
+/// over unencrypted RPC), `table` is a table name or a table name prefix
+/// followed by `*`, and `permissions` is a comma-separated list of
+/// [`Permission`]s. For instance, `*:chunks:read` allows everybody to read
+/// `chunks` table.
+#[derive(Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
+pub struct AclRule {
+    /// Client to which the rule applies; `None` matches any client
+    pub client: Option<NodeId>,
+
+    /// Table name; trailing `*` matches all tables with the given prefix
+    pub table: String,
+
+    /// Permissions granted by the rule
+    pub permissions: BTreeSet<Permission>,
+}
+
+impl AclRule {
+    /// Checks whether the rule applies to the given client and table.
+    pub fn matches(&self, client: Option<NodeId>, table: &str) -> bool {
+        let client_matches = self.client.is_none() || self.client == client;
+        let table_matches = match self.table.strip_suffix('*') {
+            Some(prefix) => table.starts_with(prefix),
+            None => self.table == table,
+        };
+        client_matches && table_matches
+    }
+
+    /// Checks whether the rule grants the permission.
+    pub fn grants(&self, permission: Permission) -> bool {
+        self.permissions.contains(&permission) || self.permissions.contains(&Permission::Admin)
+    }
+}
+
+impl Display for AclRule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.client {
+            Some(client) => write!(f, "{}:", client)?,
+            None => f.write_str("*:")?,
+        }
+        write!(f, "{}:", self.table)?;
+        let permissions = self.permissions.iter().map(Permission::to_string).collect::<Vec<_>>();
+        f.write_str(&permissions.join(","))
+    }
+}
+
+impl FromStr for AclRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (client, rest) =
+            s.split_once(':').ok_or_else(|| format!("ACL rule '{}' has no table", s))?;
+        let (table, permissions) =
+            rest.rsplit_once(':').ok_or_else(|| format!("ACL rule '{}' has no permissions", s))?;
+        let client = match client {
+            "*" => None,
+            key => Some(
+                NodeId::from_str(key)
+                    .map_err(|_| format!("invalid client public key '{}' in ACL rule", key))?,
+            ),
+        };
+        if table.is_empty() {
+            return Err(format!("ACL rule '{}' has empty table name", s));
+        }
+        let permissions = permissions
+            .split(',')
+            .map(|permission| Permission::from_str(permission.trim()))
+            .collect::<Result<_, _>>()?;
+        Ok(AclRule {
+            client,
+            table: table.to_owned(),
+            permissions,
+        })
+    }
+}
+
+/// Access control list consisting of [`AclRule`]s. Empty list allows
+/// everything to everybody; otherwise a request is allowed only if some rule
+/// grants the required permission.
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Default)]
+pub struct Acl(Vec<AclRule>);
+
+impl Acl {
+    pub fn with(rules: impl IntoIterator<Item = AclRule>) -> Self {
+        Acl(rules.into_iter().collect())
+    }
+
+    /// Returns rules of the list in the order they were given.
+    pub fn rules(&self) -> &[AclRule] { &self.0 }
+
+    /// Checks whether the client has the permission on the table.
+    pub fn is_permitted(
+        &self,
+        client: Option<NodeId>,
+        table: &str,
+        permission: Permission,
+    ) -> bool {
+        self.0.is_empty()
+            || self.0.iter().any(|rule| rule.matches(client, table) && rule.grants(permission))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use super::{Acl, AclRule, Permission};
+
+    #[test]
+    fn rule_matching() {
+        let shared = AclRule::from_str("*:chunks:read").unwrap();
+        let own = AclRule::from_str("*:app_*:read,write").unwrap();
+        assert_eq!(own.to_string(), "*:app_*:read,write");
+        assert!(AclRule::from_str("*:chunks").is_err());
+        assert!(AclRule::from_str("nokey:chunks:read").is_err());
+
+        let acl = Acl::with(vec![shared, own]);
+        assert!(acl.is_permitted(None, "chunks", Permission::Read));
+        assert!(!acl.is_permitted(None, "chunks", Permission::Write));
+        assert!(acl.is_permitted(None, "app_one", Permission::Write));
+        assert!(!acl.is_permitted(None, "app_one", Permission::Admin));
+        assert!(!acl.is_permitted(None, "other", Permission::Read));
+        assert!(Acl::default().is_permitted(None, "other", Permission::Admin));
+    }
+}
@@ -0,0 +1,721 @@
+// Storage daemon (stored): microservice frontend for different storage backends
+// used in LNP/BP nodes.
+//
+// Written in 2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// Copyright (C) 2022 by LNP/BP Standards Association, Switzerland.
+//
+// You should have received a copy of the MIT License along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+use std::collections::{BTreeSet, HashMap};
+use std::io::{self, Write};
+use std::ops::{Bound, RangeBounds};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::{fs, mem};
+
+use amplify::hex::{FromHex, ToHex};
+use amplify::Slice32;
+use nix::errno::Errno;
+use nix::fcntl::{flock, FlockArg};
+
+use super::{Entry, Overlay, StorageBackend, Table, TxFn};
+use crate::{BackendError, DaemonError, Durability, STORED_FS_DIR};
+
+/// Counter making names of temporary files unique within the process
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// File locked by the process using the storage; being dot-prefixed, it is not
+/// taken for a table
+const LOCK_FILE: &str = ".lock";
+
+/// Storage backend keeping each table as a directory and each value as a
+/// separate file named after the hex representation of its key.
+///
+/// Files are distributed into fan-out subdirectories named after the first
+/// byte of the key, i.e. value stored under key `ab01...` is kept in
+/// `<table>/ab/ab01...`.
+///
+/// The storage is locked while the backend is open, so it can't be used by
+/// several processes at once. Within the process, writes to a table are
+/// serialized, so that compare-and-swap can check the stored value and replace
+/// it atomically.
+///
+/// Values are synced to disk before they are moved into place only in
+/// [`Durability::Sync`] mode; in [`Durability::Group`] mode the written files
+/// are synced by [`Table::flush`].
+pub struct FsBackend {
+    root: PathBuf,
+    durability: Durability,
+
+    /// State shared by all instances of the same table
+    tables: Mutex<HashMap<String, Arc<Shared>>>,
+
+    /// Lock file, released once the backend is dropped
+    _lock: fs::File,
+}
+
+impl FsBackend {
+    pub fn open(data_dir: &Path, durability: Durability) -> Result<Self, BackendError> {
+        let root = data_dir.join(STORED_FS_DIR);
+        debug!("Opening file storage at {}", root.display());
+        fs::create_dir_all(&root)?;
+        let lock = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(root.join(LOCK_FILE))?;
+        match flock(lock.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+            Ok(()) => {}
+            Err(nix::Error::Sys(Errno::EAGAIN)) => {
+                return Err(BackendError::Locked(root.display().to_string()))
+            }
+            Err(nix::Error::Sys(errno)) => return Err(io::Error::from(errno).into()),
+            Err(err) => return Err(io::Error::new(io::ErrorKind::Other, err).into()),
+        }
+        Ok(FsBackend {
+            root,
+            durability,
+            tables: empty!(),
+            _lock: lock,
+        })
+    }
+
+    fn table_dir(&self, name: &str) -> Result<PathBuf, BackendError> {
+        if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+            return Err(BackendError::InvalidTableName(name.to_owned()));
+        }
+        Ok(self.root.join(name))
+    }
+
+    fn table(&self, name: &str) -> Result<FsTable, BackendError> {
+        let dir = self.table_dir(name)?;
+        let mut tables = self.tables.lock().expect("file storage lock is poisoned");
+        let shared = tables.entry(name.to_owned()).or_default();
+        Ok(FsTable {
+            dir,
+            durability: self.durability,
+            shared: Arc::clone(shared),
+        })
+    }
+}
+
+impl StorageBackend for FsBackend {
+    fn open_table(&self, name: &str) -> Result<Box<dyn Table>, BackendError> {
+        let table = self.table(name)?;
+        fs::create_dir_all(&table.dir)?;
+        Ok(Box::new(table))
+    }
+
+    fn table_names(&self) -> Result<BTreeSet<String>, BackendError> {
+        let mut names = bset![];
+        for entry in fs::read_dir(&self.root)? {
+            let entry = entry?;
+            match entry.file_name().into_string() {
+                Ok(name) if !name.starts_with('.') && entry.file_type()?.is_dir() => {
+                    names.insert(name);
+                }
+                _ => trace!("Skipping foreign file {}", entry.path().display()),
+            }
+        }
+        Ok(names)
+    }
+
+    fn drop_table(&self, name: &str) -> Result<bool, BackendError> {
+        match fs::remove_dir_all(self.table_dir(name)?) {
+            Ok(()) => Ok(true),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Directories can be renamed atomically, so we do not need to copy the
+    /// data.
+    fn rename_table(&self, from: &str, to: &str) -> Result<Box<dyn Table>, BackendError> {
+        let table = self.table(to)?;
+        fs::rename(self.table_dir(from)?, &table.dir)?;
+        Ok(Box::new(table))
+    }
+
+    /// Tables are locked for writing for the whole transaction, so concurrent
+    /// requests can't change the values it reads. Like in
+    /// [`Table::apply_batch`], values are written into temporary files before
+    /// any of them is moved into its final location.
+    fn transaction(&self, tables: &[&str], tx: &TxFn) -> Result<(), DaemonError> {
+        let tables = tables
+            .iter()
+            .map(|name| {
+                let table = self.table(name)?;
+                fs::create_dir_all(&table.dir)?;
+                Ok(table)
+            })
+            .collect::<Result<Vec<_>, BackendError>>()?;
+        // Tables are locked in the order of their names, so that concurrent
+        // transactions can't deadlock
+        let mut order = tables.iter().collect::<Vec<_>>();
+        order.sort_by(|a, b| a.dir.cmp(&b.dir));
+        let _writers = order.into_iter().map(FsTable::lock).collect::<Vec<_>>();
+
+        let overlay = Overlay::new(|no, key| tables[no].get(key));
+        tx(&overlay)?;
+        let writes = overlay.into_writes();
+        let written = stage(writes.iter().filter_map(|((no, key), value)| {
+            value.as_deref().map(|value| (&tables[*no], *key, value))
+        }))?;
+        for (tmp, path) in written {
+            fs::rename(tmp, path).map_err(BackendError::from)?;
+        }
+        for ((no, key), value) in writes {
+            if value.is_none() {
+                tables[no].delete(key)?;
+            }
+            tables[no].changed(key);
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BackendError> { Ok(()) }
+}
+
+/// State of a table shared by all its instances
+#[derive(Default)]
+struct Shared {
+    /// Lock serializing writes to the table
+    writer: Mutex<()>,
+
+    /// Files and directories changed since the last flush, which are not
+    /// synced to disk yet
+    unsynced: Mutex<BTreeSet<PathBuf>>,
+}
+
+/// Table of [`FsBackend`].
+pub struct FsTable {
+    dir: PathBuf,
+    durability: Durability,
+    shared: Arc<Shared>,
+}
+
+impl FsTable {
+    /// Locks the table for writing by other instances of the table.
+    fn lock(&self) -> MutexGuard<'_, ()> {
+        self.shared.writer.lock().expect("file table lock is poisoned")
+    }
+
+    fn unsynced(&self) -> MutexGuard<'_, BTreeSet<PathBuf>> {
+        self.shared.unsynced.lock().expect("file table lock is poisoned")
+    }
+
+    /// Remembers that the value stored under the key was changed, so that the
+    /// change is synced to disk on the next flush. Values written in
+    /// [`Durability::Sync`] mode are already synced, so only their directory
+    /// entries are left; nothing is tracked if flushing is left to the OS.
+    fn changed(&self, key: Slice32) {
+        match self.durability {
+            Durability::Sync => {
+                self.unsynced().insert(self.fanout_dir(key));
+            }
+            Durability::Group => {
+                self.unsynced().extend([self.fanout_dir(key), self.path(key)]);
+            }
+            Durability::Os => {}
+        }
+    }
+
+    fn fanout_dir(&self, key: Slice32) -> PathBuf { self.dir.join(format!("{:02x}", key[0usize])) }
+
+    fn path(&self, key: Slice32) -> PathBuf { self.fanout_dir(key).join(key.to_hex()) }
+
+    /// Writes value into a temporary file next to its final location, returning
+    /// path to the temporary file.
+    fn write_tmp(&self, key: Slice32, value: &[u8]) -> Result<PathBuf, BackendError> {
+        let dir = self.fanout_dir(key);
+        fs::create_dir_all(&dir)?;
+        let tmp = dir.join(format!(
+            ".{}.{}.{}.tmp",
+            key.to_hex(),
+            std::process::id(),
+            TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(value)?;
+        if self.durability == Durability::Sync {
+            file.sync_all()?;
+        }
+        Ok(tmp)
+    }
+
+    /// Removes file with the value stored under the key, if any, without
+    /// locking the table.
+    fn delete(&self, key: Slice32) -> Result<(), BackendError> {
+        match fs::remove_file(self.path(key)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn read(path: &Path) -> Result<Option<Vec<u8>>, BackendError> {
+        match fs::read(path) {
+            Ok(data) => Ok(Some(data)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Lists entries of a directory which names can be parsed, in ascending
+    /// order. Temporary and foreign files are skipped.
+    fn sorted_entries<T>(
+        dir: &Path,
+        parse: impl Fn(&str) -> Option<T>,
+    ) -> Result<Vec<(T, PathBuf)>, BackendError>
+    where
+        T: Ord,
+    {
+        let mut entries = vec![];
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            match name.to_str().and_then(&parse) {
+                Some(parsed) => entries.push((parsed, entry.path())),
+                None => trace!("Skipping foreign file {}", entry.path().display()),
+            }
+        }
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(entries)
+    }
+
+    fn walk<'me>(
+        &'me self,
+        start: Bound<Slice32>,
+        end: Bound<Slice32>,
+    ) -> Result<impl Iterator<Item = Result<(Slice32, PathBuf), BackendError>> + 'me, BackendError>
+    {
+        let fanouts = Self::sorted_entries(&self.dir, |name| {
+            if name.len() == 2 {
+                u8::from_str_radix(name, 16).ok()
+            } else {
+                None
+            }
+        })?;
+        let (first, last) = (bound_byte(&start).unwrap_or(0), bound_byte(&end).unwrap_or(0xFF));
+        Ok(fanouts
+            .into_iter()
+            .filter(move |(byte, _)| *byte >= first && *byte <= last)
+            .flat_map(|(_, dir)| {
+                match Self::sorted_entries(&dir, |name| {
+                    <[u8; 32]>::from_hex(name).ok().map(Slice32::from)
+                }) {
+                    Ok(entries) => entries.into_iter().map(Ok).collect::<Vec<_>>(),
+                    Err(err) => vec![Err(err)],
+                }
+            })
+            .filter(move |res| match res {
+                Ok((key, _)) => (start, end).contains(key),
+                Err(_) => true,
+            }))
+    }
+}
+
+impl Table for FsTable {
+    fn len(&self) -> Result<usize, BackendError> {
+        self.walk(Bound::Unbounded, Bound::Unbounded)?.try_fold(0, |count, res| {
+            res?;
+            Ok(count + 1)
+        })
+    }
+
+    fn get(&self, key: Slice32) -> Result<Option<Vec<u8>>, BackendError> {
+        Self::read(&self.path(key))
+    }
+
+    fn contains_key(&self, key: Slice32) -> Result<bool, BackendError> {
+        Ok(self.path(key).is_file())
+    }
+
+    fn insert(&self, key: Slice32, value: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
+        let _writer = self.lock();
+        let path = self.path(key);
+        let prev = Self::read(&path)?;
+        let tmp = self.write_tmp(key, value)?;
+        fs::rename(tmp, path)?;
+        self.changed(key);
+        Ok(prev)
+    }
+
+    fn remove(&self, key: Slice32) -> Result<Option<Vec<u8>>, BackendError> {
+        let _writer = self.lock();
+        let path = self.path(key);
+        let prev = Self::read(&path)?;
+        if prev.is_some() {
+            fs::remove_file(path)?;
+            self.changed(key);
+        }
+        Ok(prev)
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: Slice32,
+        old: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<Result<(), Option<Vec<u8>>>, BackendError> {
+        let _writer = self.lock();
+        let path = self.path(key);
+        let current = Self::read(&path)?;
+        if current.as_deref() != old {
+            return Ok(Err(current));
+        }
+        match new {
+            Some(new) => fs::rename(self.write_tmp(key, new)?, path)?,
+            None if current.is_some() => fs::remove_file(path)?,
+            None => return Ok(Ok(())),
+        }
+        self.changed(key);
+        Ok(Ok(()))
+    }
+
+    fn clear(&self) -> Result<(), BackendError> {
+        let _writer = self.lock();
+        fs::remove_dir_all(&self.dir)?;
+        fs::create_dir_all(&self.dir)?;
+        if self.durability != Durability::Os {
+            self.unsynced().insert(self.dir.clone());
+        }
+        Ok(())
+    }
+
+    /// File system does not support atomic writes of multiple files. We write
+    /// all values into temporary files first, so that an I/O failure leaves
+    /// the table unchanged, and then rename them into their final locations.
+    fn apply_batch(&self, items: Vec<(Slice32, Vec<u8>)>) -> Result<(), BackendError> {
+        let _writer = self.lock();
+        let written = stage(items.iter().map(|(key, value)| (self, *key, value.as_slice())))?;
+        for (tmp, path) in written {
+            fs::rename(tmp, path)?;
+        }
+        for (key, _) in items {
+            self.changed(key);
+        }
+        Ok(())
+    }
+
+    fn range<'me>(
+        &'me self,
+        start: Bound<Slice32>,
+        end: Bound<Slice32>,
+    ) -> Box<dyn Iterator<Item = Result<Entry, BackendError>> + 'me> {
+        match self.walk(start, end) {
+            // Files removed after the directory was listed are skipped
+            Ok(iter) => Box::new(iter.filter_map(|res| {
+                let (key, path) = match res {
+                    Ok(item) => item,
+                    Err(err) => return Some(Err(err)),
+                };
+                Self::read(&path).transpose().map(|value| Ok((key.to_vec(), value?)))
+            })),
+            Err(err) => Box::new(Some(Err(err)).into_iter()),
+        }
+    }
+
+    /// Files are read one by one, so the table is locked for writing until the
+    /// iterator is dropped.
+    fn snapshot<'me>(&'me self) -> Box<dyn Iterator<Item = Result<Entry, BackendError>> + 'me> {
+        Box::new(Locked {
+            _writer: self.lock(),
+            entries: self.range(Bound::Unbounded, Bound::Unbounded),
+        })
+    }
+
+    /// Syncs files and directories changed since the last flush. Entries
+    /// removed in the meantime are skipped.
+    fn flush(&self) -> Result<(), BackendError> {
+        let unsynced = mem::take(&mut *self.unsynced());
+        for (no, path) in unsynced.iter().enumerate() {
+            match fs::File::open(path).and_then(|file| file.sync_all()) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                    self.unsynced().extend(unsynced.into_iter().skip(no));
+                    return Err(err.into());
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Iterator keeping the table locked for writing while it is alive
+struct Locked<'table, I> {
+    _writer: MutexGuard<'table, ()>,
+    entries: I,
+}
+
+impl<'table, I> Iterator for Locked<'table, I>
+where I: Iterator
+{
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<Self::Item> { self.entries.next() }
+}
+
+/// Writes values into temporary files next to their final locations, returning
+/// pairs of temporary and final paths. If any of the values can't be written,
+/// temporary files are removed and the error is returned.
+fn stage<'table>(
+    items: impl IntoIterator<Item = (&'table FsTable, Slice32, &'table [u8])>,
+) -> Result<Vec<(PathBuf, PathBuf)>, BackendError> {
+    let mut written = vec![];
+    for (table, key, value) in items {
+        match table.write_tmp(key, value) {
+            Ok(tmp) => written.push((tmp, table.path(key))),
+            Err(err) => {
+                for (tmp, _) in written {
+                    let _ = fs::remove_file(tmp);
+                }
+                return Err(err);
+            }
+        }
+    }
+    Ok(written)
+}
+
+/// Returns first byte of the key used as a range bound, which determines the
+/// fan-out directory.
+fn bound_byte(bound: &Bound<Slice32>) -> Option<u8> {
+    match bound {
+        Bound::Included(key) | Bound::Excluded(key) => Some(key[0usize]),
+        Bound::Unbounded => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::ops::Bound;
+    use std::thread;
+    use std::time::Duration;
+
+    use amplify::Slice32;
+
+    use super::FsBackend;
+    use crate::backend::{StorageBackend, Table};
+    use crate::{BackendError, DaemonError, Durability, STORED_FS_DIR};
+
+    fn key(first: u8, last: u8) -> Slice32 {
+        let mut key = [0u8; 32];
+        key[0] = first;
+        key[31] = last;
+        Slice32::from(key)
+    }
+
+    #[test]
+    fn table_ops() {
+        let dir = std::env::temp_dir().join(format!("stored-fs-test-{}", std::process::id()));
+        let backend = FsBackend::open(&dir, Durability::Sync).unwrap();
+        assert!(backend.open_table("../escape").is_err());
+        let table = backend.open_table("test").unwrap();
+        assert!(table.is_empty().unwrap());
+        assert_eq!(backend.table_names().unwrap(), bset![s!("test")]);
+
+        assert_eq!(table.insert(key(0xab, 1), b"one").unwrap(), None);
+        assert_eq!(table.insert(key(0xab, 1), b"uno").unwrap(), Some(b"one".to_vec()));
+        table
+            .apply_batch(vec![(key(0x01, 2), b"two".to_vec()), (key(0xff, 3), b"three".to_vec())])
+            .unwrap();
+        assert_eq!(table.len().unwrap(), 3);
+        assert_eq!(table.get(key(0xab, 1)).unwrap(), Some(b"uno".to_vec()));
+        assert!(dir.join("tables/test/ab").join(key(0xab, 1).to_string()).is_file());
+
+        let keys = table
+            .range(Bound::Excluded(key(0x01, 2)), Bound::Unbounded)
+            .map(|res| res.unwrap().0)
+            .collect::<Vec<_>>();
+        assert_eq!(keys, vec![key(0xab, 1).to_vec(), key(0xff, 3).to_vec()]);
+
+        assert_eq!(table.remove(key(0xff, 3)).unwrap(), Some(b"three".to_vec()));
+        assert!(!table.contains_key(key(0xff, 3)).unwrap());
+        assert_eq!(table.len().unwrap(), 2);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn lock() {
+        let dir = std::env::temp_dir().join(format!("stored-fs-lock-{}", std::process::id()));
+        let backend = FsBackend::open(&dir, Durability::Sync).unwrap();
+        let root = dir.join(STORED_FS_DIR);
+        assert_eq!(
+            FsBackend::open(&dir, Durability::Sync).err(),
+            Some(BackendError::Locked(root.display().to_string()))
+        );
+        assert!(backend.table_names().unwrap().is_empty());
+
+        drop(backend);
+        FsBackend::open(&dir, Durability::Sync).unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn concurrent_removal() {
+        let dir = std::env::temp_dir().join(format!("stored-fs-removal-{}", std::process::id()));
+        let backend = FsBackend::open(&dir, Durability::Sync).unwrap();
+        let table = backend.open_table("test").unwrap();
+        table
+            .apply_batch(vec![(key(0xab, 1), b"one".to_vec()), (key(0xab, 2), b"two".to_vec())])
+            .unwrap();
+
+        // Entry removed after its directory was listed is skipped
+        let mut iter = table.range(Bound::Unbounded, Bound::Unbounded);
+        assert_eq!(iter.next().unwrap().unwrap(), (key(0xab, 1).to_vec(), b"one".to_vec()));
+        table.remove(key(0xab, 2)).unwrap();
+        assert!(iter.next().is_none());
+        drop(iter);
+
+        // Writes wait until the snapshot is iterated
+        let mut snapshot = table.snapshot();
+        let writer = {
+            let table = backend.open_table("test").unwrap();
+            thread::spawn(move || table.insert(key(0xab, 3), b"three").unwrap())
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(snapshot.next().unwrap().unwrap(), (key(0xab, 1).to_vec(), b"one".to_vec()));
+        assert!(snapshot.next().is_none());
+        drop(snapshot);
+        writer.join().unwrap();
+        assert_eq!(table.len().unwrap(), 2);
+
+        // Table which can't be read reports an error instead of being empty
+        assert!(backend.drop_table("test").unwrap());
+        assert!(table.len().is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn compare_and_swap() {
+        let dir = std::env::temp_dir().join(format!("stored-fs-cas-{}", std::process::id()));
+        let backend = FsBackend::open(&dir, Durability::Sync).unwrap();
+        let table = backend.open_table("test").unwrap();
+        assert_eq!(table.compare_and_swap(key(1, 1), None, Some(b"one")).unwrap(), Ok(()));
+        assert_eq!(
+            table.compare_and_swap(key(1, 1), None, Some(b"two")).unwrap(),
+            Err(Some(b"one".to_vec()))
+        );
+        assert_eq!(table.compare_and_swap(key(1, 1), Some(b"one"), None).unwrap(), Ok(()));
+        assert!(table.is_empty().unwrap());
+
+        // Instances of the same table opened separately swap values atomically
+        let writers = (0..4)
+            .map(|_| {
+                let table = backend.open_table("test").unwrap();
+                thread::spawn(move || {
+                    for _ in 0..25 {
+                        let mut current = table.get(key(1, 1)).unwrap();
+                        loop {
+                            let count = current.as_deref().map(|data| data[0]).unwrap_or(0);
+                            let new = [count + 1];
+                            match table
+                                .compare_and_swap(key(1, 1), current.as_deref(), Some(&new))
+                                .unwrap()
+                            {
+                                Ok(()) => break,
+                                Err(actual) => current = actual,
+                            }
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        writers.into_iter().for_each(|writer| writer.join().unwrap());
+        assert_eq!(table.get(key(1, 1)).unwrap(), Some(vec![100]));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn transaction() {
+        let dir = std::env::temp_dir().join(format!("stored-fs-tx-{}", std::process::id()));
+        let backend = FsBackend::open(&dir, Durability::Sync).unwrap();
+        let chunks = backend.open_table("chunks").unwrap();
+        let index = backend.open_table("index").unwrap();
+        chunks.insert(key(1, 1), b"one").unwrap();
+
+        backend
+            .transaction(&["chunks", "index"], &|view| {
+                view.insert(0, key(2, 2), b"two")?;
+                assert_eq!(view.remove(0, key(1, 1))?, Some(b"one".to_vec()));
+                view.insert(1, key(2, 2), b"index")?;
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(chunks.get(key(1, 1)).unwrap(), None);
+        assert_eq!(chunks.get(key(2, 2)).unwrap(), Some(b"two".to_vec()));
+        assert_eq!(index.get(key(2, 2)).unwrap(), Some(b"index".to_vec()));
+
+        // Failed transaction leaves all tables intact
+        let err = backend
+            .transaction(&["chunks", "index"], &|view| {
+                view.remove(0, key(2, 2))?;
+                Err(DaemonError::UnknownTable(s!("none")))
+            })
+            .unwrap_err();
+        assert_eq!(err, DaemonError::UnknownTable(s!("none")));
+        assert_eq!(chunks.get(key(2, 2)).unwrap(), Some(b"two".to_vec()));
+
+        // Concurrent writes wait for the transaction to complete
+        let writer = RefCell::new(None);
+        backend
+            .transaction(&["chunks"], &|view| {
+                let chunks = backend.open_table("chunks")?;
+                *writer.borrow_mut() =
+                    Some(thread::spawn(move || chunks.insert(key(2, 2), b"concurrent").unwrap()));
+                thread::sleep(Duration::from_millis(50));
+                assert_eq!(view.get(0, key(2, 2))?, Some(b"two".to_vec()));
+                view.insert(0, key(2, 2), b"tx")?;
+                Ok(())
+            })
+            .unwrap();
+        writer.into_inner().unwrap().join().unwrap();
+        assert_eq!(chunks.get(key(2, 2)).unwrap(), Some(b"concurrent".to_vec()));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn durability() {
+        let dir = std::env::temp_dir().join(format!("stored-fs-durability-{}", std::process::id()));
+        let backend = FsBackend::open(&dir, Durability::Group).unwrap();
+        let table = backend.table("test").unwrap();
+        table.insert(key(0xab, 1), b"one").unwrap();
+        table.apply_batch(vec![(key(0xcd, 2), b"two".to_vec())]).unwrap();
+        table.remove(key(0xab, 1)).unwrap();
+
+        // Written files are synced on flush, together with their directories
+        let unsynced = table.unsynced().clone();
+        assert_eq!(unsynced, bset![
+            table.fanout_dir(key(0xab, 1)),
+            table.path(key(0xab, 1)),
+            table.fanout_dir(key(0xcd, 2)),
+            table.path(key(0xcd, 2))
+        ]);
+        // Other instances of the table flush the same files
+        backend.open_table("test").unwrap().flush().unwrap();
+        assert!(table.unsynced().is_empty());
+        drop(backend);
+
+        let backend = FsBackend::open(&dir, Durability::Sync).unwrap();
+        let table = backend.table("test").unwrap();
+        table.insert(key(0xab, 1), b"one").unwrap();
+        assert_eq!(*table.unsynced(), bset![table.fanout_dir(key(0xab, 1))]);
+        drop(backend);
+
+        let backend = FsBackend::open(&dir, Durability::Os).unwrap();
+        let table = backend.table("test").unwrap();
+        table.insert(key(0xab, 2), b"two").unwrap();
+        assert!(table.unsynced().is_empty());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,40 @@
+// Storage daemon (stored): microservice frontend for different storage backends
+// used in LNP/BP nodes.
+//
+// Written in 2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// Copyright (C) 2022 by LNP/BP Standards Association, Switzerland.
+//
+// You should have received a copy of the MIT License along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+use std::str::FromStr;
+
+/// Types of storage backends supported by the daemon
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug, Display)]
+#[display(lowercase)]
+pub enum BackendType {
+    /// Embedded sled database
+    Sled,
+
+    /// Volatile in-memory storage, which does not persist data between
+    /// restarts
+    Memory,
+
+    /// Plain file system directory keeping each value in a separate file
+    Fs,
+}
+
+impl FromStr for BackendType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "sled" => Ok(BackendType::Sled),
+            "memory" => Ok(BackendType::Memory),
+            "fs" => Ok(BackendType::Fs),
+            other => Err(format!("unknown storage backend '{}'", other)),
+        }
+    }
+}
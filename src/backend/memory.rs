@@ -0,0 +1,368 @@
+// Storage daemon (stored): microservice frontend for different storage backends
+// used in LNP/BP nodes.
+//
+// Written in 2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// Copyright (C) 2022 by LNP/BP Standards Association, Switzerland.
+//
+// You should have received a copy of the MIT License along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::ops::Bound;
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use amplify::Slice32;
+
+use super::{Entry, Overlay, StorageBackend, Table, TxFn};
+use crate::{BackendError, DaemonError};
+
+/// Number of entries copied from a table at once while iterating over a range
+const RANGE_BATCH: usize = 256;
+
+/// Storage backend keeping all the data in memory. The data are lost once the
+/// daemon stops.
+#[derive(Default)]
+pub struct MemoryBackend {
+    tables: Mutex<HashMap<String, MemoryTable>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self { MemoryBackend::default() }
+}
+
+impl StorageBackend for MemoryBackend {
+    fn open_table(&self, name: &str) -> Result<Box<dyn Table>, BackendError> {
+        let mut tables = self.tables.lock().expect("memory backend lock is poisoned");
+        let table = tables.entry(name.to_owned()).or_default();
+        Ok(Box::new(table.clone()))
+    }
+
+    fn table_names(&self) -> Result<BTreeSet<String>, BackendError> {
+        let tables = self.tables.lock().expect("memory backend lock is poisoned");
+        Ok(tables.keys().cloned().collect())
+    }
+
+    fn drop_table(&self, name: &str) -> Result<bool, BackendError> {
+        let mut tables = self.tables.lock().expect("memory backend lock is poisoned");
+        Ok(tables.remove(name).is_some())
+    }
+
+    /// Tables are write-locked for the whole transaction, so concurrent
+    /// requests neither see its partial writes nor change the values it reads.
+    fn transaction(&self, tables: &[&str], tx: &TxFn) -> Result<(), DaemonError> {
+        let opened = {
+            let mut known = self.tables.lock().expect("memory backend lock is poisoned");
+            tables
+                .iter()
+                .map(|name| known.entry((*name).to_owned()).or_default().clone())
+                .collect::<Vec<_>>()
+        };
+        // Tables are locked in the order of their names, so that concurrent
+        // transactions can't deadlock
+        let mut order = (0..tables.len()).collect::<Vec<_>>();
+        order.sort_by_key(|no| tables[*no]);
+        let mut locked = order.into_iter().map(|no| (no, opened[no].write())).collect::<Vec<_>>();
+        locked.sort_by_key(|(no, _)| *no);
+        let mut maps = locked.into_iter().map(|(_, map)| map).collect::<Vec<_>>();
+
+        let overlay = Overlay::new(|no, key| Ok(maps[no].get(&key[..]).cloned()));
+        tx(&overlay)?;
+        for ((no, key), value) in overlay.into_writes() {
+            match value {
+                Some(value) => maps[no].insert(key.to_vec(), value),
+                None => maps[no].remove(&key[..]),
+            };
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BackendError> { Ok(()) }
+}
+
+/// Table of [`MemoryBackend`]. Clones of the table share the same data.
+#[derive(Clone, Default)]
+pub struct MemoryTable(Arc<RwLock<BTreeMap<Vec<u8>, Vec<u8>>>>);
+
+impl MemoryTable {
+    fn read(&self) -> RwLockReadGuard<'_, BTreeMap<Vec<u8>, Vec<u8>>> {
+        self.0.read().expect("memory table lock is poisoned")
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, BTreeMap<Vec<u8>, Vec<u8>>> {
+        self.0.write().expect("memory table lock is poisoned")
+    }
+}
+
+impl Table for MemoryTable {
+    fn len(&self) -> Result<usize, BackendError> { Ok(self.read().len()) }
+
+    fn get(&self, key: Slice32) -> Result<Option<Vec<u8>>, BackendError> {
+        Ok(self.read().get(&key[..]).cloned())
+    }
+
+    fn contains_key(&self, key: Slice32) -> Result<bool, BackendError> {
+        Ok(self.read().contains_key(&key[..]))
+    }
+
+    fn insert(&self, key: Slice32, value: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
+        Ok(self.write().insert(key.to_vec(), value.to_vec()))
+    }
+
+    fn remove(&self, key: Slice32) -> Result<Option<Vec<u8>>, BackendError> {
+        Ok(self.write().remove(&key[..]))
+    }
+
+    fn remove_raw(&self, key: &[u8]) -> Result<(), BackendError> {
+        self.write().remove(key);
+        Ok(())
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: Slice32,
+        old: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<Result<(), Option<Vec<u8>>>, BackendError> {
+        let mut map = self.write();
+        let current = map.get(&key[..]);
+        if current.map(Vec::as_slice) != old {
+            return Ok(Err(current.cloned()));
+        }
+        match new {
+            Some(new) => map.insert(key.to_vec(), new.to_vec()),
+            None => map.remove(&key[..]),
+        };
+        Ok(Ok(()))
+    }
+
+    fn clear(&self) -> Result<(), BackendError> {
+        self.write().clear();
+        Ok(())
+    }
+
+    fn apply_batch(&self, items: Vec<(Slice32, Vec<u8>)>) -> Result<(), BackendError> {
+        let mut map = self.write();
+        for (key, value) in items {
+            map.insert(key.to_vec(), value);
+        }
+        Ok(())
+    }
+
+    fn range<'me>(
+        &'me self,
+        start: Bound<Slice32>,
+        end: Bound<Slice32>,
+    ) -> Box<dyn Iterator<Item = Result<Entry, BackendError>> + 'me> {
+        let start = to_vec_bound(start);
+        let end = to_vec_bound(end);
+        Box::new(MemoryRange {
+            table: self,
+            done: is_empty_range(&start, &end),
+            start,
+            end,
+            batch: VecDeque::new(),
+        })
+    }
+
+    /// Range iterator does not hold the lock between batches, so the data are
+    /// copied at once.
+    fn snapshot<'me>(&'me self) -> Box<dyn Iterator<Item = Result<Entry, BackendError>> + 'me> {
+        Box::new(self.read().clone().into_iter().map(Ok))
+    }
+
+    fn flush(&self) -> Result<(), BackendError> { Ok(()) }
+}
+
+/// Iterator over entries of [`MemoryTable`], which copies them in small
+/// batches, so that the lock is not held during iteration and reading only the
+/// first entries of a large range is cheap.
+struct MemoryRange<'table> {
+    table: &'table MemoryTable,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    batch: VecDeque<Entry>,
+    done: bool,
+}
+
+impl<'table> Iterator for MemoryRange<'table> {
+    type Item = Result<Entry, BackendError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.batch.is_empty() && !self.done {
+            self.batch = self
+                .table
+                .read()
+                .range((self.start.clone(), self.end.clone()))
+                .take(RANGE_BATCH)
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect();
+            match self.batch.back() {
+                Some((key, _)) if self.batch.len() == RANGE_BATCH => {
+                    self.start = Bound::Excluded(key.clone());
+                    self.done = is_empty_range(&self.start, &self.end);
+                }
+                _ => self.done = true,
+            }
+        }
+        self.batch.pop_front().map(Ok)
+    }
+}
+
+/// Detects ranges which contain no keys, including the ones which
+/// [`BTreeMap::range`] does not accept.
+fn is_empty_range(start: &Bound<Vec<u8>>, end: &Bound<Vec<u8>>) -> bool {
+    match (start, end) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end))
+        | (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+        _ => false,
+    }
+}
+
+fn to_vec_bound(bound: Bound<Slice32>) -> Bound<Vec<u8>> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.to_vec()),
+        Bound::Excluded(key) => Bound::Excluded(key.to_vec()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::ops::Bound;
+    use std::thread;
+    use std::time::Duration;
+
+    use amplify::Slice32;
+
+    use super::MemoryBackend;
+    use crate::backend::StorageBackend;
+    use crate::DaemonError;
+
+    fn key(byte: u8) -> Slice32 { Slice32::from([byte; 32]) }
+
+    #[test]
+    fn table_ops() {
+        let backend = MemoryBackend::new();
+        let table = backend.open_table("test").unwrap();
+        assert!(table.is_empty().unwrap());
+
+        assert_eq!(table.insert(key(2), b"two").unwrap(), None);
+        table.apply_batch(vec![(key(1), b"one".to_vec()), (key(3), b"three".to_vec())]).unwrap();
+        assert_eq!(table.len().unwrap(), 3);
+        assert_eq!(table.get(key(1)).unwrap(), Some(b"one".to_vec()));
+        assert!(table.contains_key(key(3)).unwrap());
+
+        assert_eq!(table.compare_and_swap(key(4), None, Some(b"four")).unwrap(), Ok(()));
+        assert_eq!(
+            table.compare_and_swap(key(4), None, Some(b"five")).unwrap(),
+            Err(Some(b"four".to_vec()))
+        );
+        assert_eq!(table.compare_and_swap(key(4), Some(b"four"), Some(b"five")).unwrap(), Ok(()));
+        assert_eq!(table.remove(key(4)).unwrap(), Some(b"five".to_vec()));
+
+        let keys = table
+            .range(Bound::Excluded(key(1)), Bound::Unbounded)
+            .map(|res| res.unwrap().0)
+            .collect::<Vec<_>>();
+        assert_eq!(keys, vec![key(2).to_vec(), key(3).to_vec()]);
+
+        assert_eq!(table.remove(key(2)).unwrap(), Some(b"two".to_vec()));
+        assert_eq!(table.get(key(2)).unwrap(), None);
+
+        // Reopened table shares the data
+        let reopened = backend.open_table("test").unwrap();
+        assert_eq!(reopened.len().unwrap(), 2);
+
+        let renamed = backend.rename_table("test", "renamed").unwrap();
+        assert_eq!(renamed.len().unwrap(), 2);
+        assert_eq!(backend.table_names().unwrap(), bset![s!("renamed")]);
+        renamed.clear().unwrap();
+        assert!(renamed.is_empty().unwrap());
+        assert!(backend.drop_table("renamed").unwrap());
+        assert!(!backend.drop_table("renamed").unwrap());
+    }
+
+    #[test]
+    fn range_batches() {
+        let backend = MemoryBackend::new();
+        let table = backend.open_table("test").unwrap();
+        let items = (0..=255u8).chain(0..=255).enumerate().map(|(no, byte)| {
+            let mut key = [byte; 32];
+            key[31] = (no / 256) as u8;
+            (Slice32::from(key), vec![byte])
+        });
+        table.apply_batch(items.collect()).unwrap();
+
+        let keys = table.range(Bound::Unbounded, Bound::Unbounded).map(|res| res.unwrap().0);
+        assert_eq!(keys.count(), 512);
+        let keys = table
+            .range(Bound::Excluded(key(1)), Bound::Included(key(0xF0)))
+            .map(|res| res.unwrap().0)
+            .collect::<Vec<_>>();
+        assert_eq!(keys.len(), (0xF0 - 1) * 2);
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(table.range(Bound::Excluded(key(1)), Bound::Excluded(key(1))).count(), 0);
+        assert_eq!(table.range(Bound::Included(key(2)), Bound::Excluded(key(1))).count(), 0);
+
+        // Snapshot is not affected by writes done while it is iterated
+        let mut snapshot = table.snapshot();
+        snapshot.next().unwrap().unwrap();
+        table.clear().unwrap();
+        assert_eq!(snapshot.count(), 511);
+    }
+
+    #[test]
+    fn transaction() {
+        let backend = MemoryBackend::new();
+        let chunks = backend.open_table("chunks").unwrap();
+        let index = backend.open_table("index").unwrap();
+        chunks.insert(key(1), b"one").unwrap();
+
+        backend
+            .transaction(&["chunks", "index"], &|view| {
+                view.insert(0, key(2), b"two")?;
+                assert_eq!(view.get(0, key(2))?, Some(b"two".to_vec()));
+                assert_eq!(view.remove(0, key(1))?, Some(b"one".to_vec()));
+                assert_eq!(view.get(0, key(1))?, None);
+                view.insert(1, key(2), b"index")?;
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(chunks.get(key(1)).unwrap(), None);
+        assert_eq!(chunks.get(key(2)).unwrap(), Some(b"two".to_vec()));
+        assert_eq!(index.get(key(2)).unwrap(), Some(b"index".to_vec()));
+
+        // Failed transaction leaves all tables intact
+        let err = backend
+            .transaction(&["chunks", "index"], &|view| {
+                view.insert(1, key(3), b"index")?;
+                view.remove(0, key(2))?;
+                Err(DaemonError::UnknownTable(s!("none")))
+            })
+            .unwrap_err();
+        assert_eq!(err, DaemonError::UnknownTable(s!("none")));
+        assert_eq!(chunks.get(key(2)).unwrap(), Some(b"two".to_vec()));
+        assert_eq!(index.len().unwrap(), 1);
+
+        // Concurrent writes wait for the transaction to complete
+        let writer = RefCell::new(None);
+        backend
+            .transaction(&["chunks"], &|view| {
+                let chunks = backend.open_table("chunks")?;
+                *writer.borrow_mut() =
+                    Some(thread::spawn(move || chunks.insert(key(2), b"concurrent").unwrap()));
+                thread::sleep(Duration::from_millis(50));
+                assert_eq!(view.get(0, key(2))?, Some(b"two".to_vec()));
+                view.insert(0, key(2), b"tx")?;
+                Ok(())
+            })
+            .unwrap();
+        writer.into_inner().unwrap().join().unwrap();
+        assert_eq!(chunks.get(key(2)).unwrap(), Some(b"concurrent".to_vec()));
+    }
+}
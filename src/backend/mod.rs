@@ -0,0 +1,265 @@
+// Storage daemon (stored): microservice frontend for different storage backends
+// used in LNP/BP nodes.
+//
+// Written in 2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// Copyright (C) 2022 by LNP/BP Standards Association, Switzerland.
+//
+// You should have received a copy of the MIT License along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Storage backends which can be used by the daemon runtime.
+
+mod fs;
+mod kind;
+mod memory;
+mod sled_db;
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
+
+use amplify::Slice32;
+
+pub use self::fs::{FsBackend, FsTable};
+pub use self::kind::BackendType;
+pub use self::memory::{MemoryBackend, MemoryTable};
+pub use self::sled_db::SledBackend;
+use crate::{BackendError, Config, DaemonError, LaunchError};
+
+/// Key-value pair read from a table
+pub type Entry = (Vec<u8>, Vec<u8>);
+
+/// Closure performing operations of a transaction. It may be called multiple
+/// times if the backend has to retry the transaction.
+pub type TxFn<'f> = dyn Fn(&dyn TxView) -> Result<(), DaemonError> + 'f;
+
+/// Storage engine keeping a number of named tables.
+pub trait StorageBackend: Send + Sync {
+    /// Opens table with the given name, creating it if it does not exist.
+    fn open_table(&self, name: &str) -> Result<Box<dyn Table>, BackendError>;
+
+    /// Lists names of all tables existing in the storage, including the ones
+    /// which were not opened yet.
+    fn table_names(&self) -> Result<BTreeSet<String>, BackendError>;
+
+    /// Removes table with all its data from the storage. Returns whether the
+    /// table has existed.
+    fn drop_table(&self, name: &str) -> Result<bool, BackendError>;
+
+    /// Renames table, returning it under the new name. The caller must ensure
+    /// that there is no table named `to`.
+    ///
+    /// Default implementation copies all the data into a new table and drops
+    /// the old one afterwards.
+    fn rename_table(&self, from: &str, to: &str) -> Result<Box<dyn Table>, BackendError> {
+        let source = self.open_table(from)?;
+        let target = self.open_table(to)?;
+        let items = source
+            .range(Bound::Unbounded, Bound::Unbounded)
+            .map(|res| {
+                let (key, value) = res?;
+                let key = Slice32::from_slice(&key).ok_or(BackendError::InvalidKey(key.len()))?;
+                Ok((key, value))
+            })
+            .collect::<Result<Vec<_>, BackendError>>()?;
+        target.apply_batch(items)?;
+        target.flush()?;
+        self.drop_table(from)?;
+        Ok(target)
+    }
+
+    /// Runs `tx` over the tables with the given distinct names atomically:
+    /// either all the writes done by `tx` are applied, or none of them if `tx`
+    /// fails.
+    ///
+    /// Default implementation keeps the writes in memory until `tx` completes
+    /// and then applies them one by one. It is atomic with respect to `tx`
+    /// failures, but not to crashes and concurrent writes.
+    fn transaction(&self, tables: &[&str], tx: &TxFn) -> Result<(), DaemonError> {
+        let tables =
+            tables.iter().map(|name| self.open_table(name)).collect::<Result<Vec<_>, _>>()?;
+        let overlay = Overlay::new(|no, key| tables[no].get(key));
+        tx(&overlay)?;
+        for ((no, key), value) in overlay.into_writes() {
+            match value {
+                Some(value) => tables[no].insert(key, &value)?,
+                None => tables[no].remove(key)?,
+            };
+        }
+        Ok(())
+    }
+
+    /// Makes all data written to the storage durable.
+    fn flush(&self) -> Result<(), BackendError>;
+}
+
+/// Tables participating in a transaction, addressed by their index in the list
+/// of tables provided to [`StorageBackend::transaction`].
+pub trait TxView {
+    /// Returns value stored under the key, if any.
+    fn get(&self, table: usize, key: Slice32) -> Result<Option<Vec<u8>>, BackendError>;
+
+    /// Stores value under the key, returning the value which was previously
+    /// stored.
+    fn insert(
+        &self,
+        table: usize,
+        key: Slice32,
+        value: &[u8],
+    ) -> Result<Option<Vec<u8>>, BackendError>;
+
+    /// Removes value stored under the key, returning it.
+    fn remove(&self, table: usize, key: Slice32) -> Result<Option<Vec<u8>>, BackendError>;
+}
+
+/// Values written by a transaction, indexed by table number and key; `None`
+/// stands for removed values
+type TxWrites = BTreeMap<(usize, Slice32), Option<Vec<u8>>>;
+
+/// Transaction view which keeps all writes in memory until the transaction
+/// completes, reading values which were not written from the tables.
+struct Overlay<F>
+where F: Fn(usize, Slice32) -> Result<Option<Vec<u8>>, BackendError>
+{
+    read: F,
+    writes: RefCell<TxWrites>,
+}
+
+impl<F> Overlay<F>
+where F: Fn(usize, Slice32) -> Result<Option<Vec<u8>>, BackendError>
+{
+    /// Constructs overlay reading values from the tables with `read`.
+    fn new(read: F) -> Self {
+        Overlay {
+            read,
+            writes: empty!(),
+        }
+    }
+
+    /// Returns all values written in the transaction.
+    fn into_writes(self) -> TxWrites { self.writes.into_inner() }
+}
+
+impl<F> TxView for Overlay<F>
+where F: Fn(usize, Slice32) -> Result<Option<Vec<u8>>, BackendError>
+{
+    fn get(&self, table: usize, key: Slice32) -> Result<Option<Vec<u8>>, BackendError> {
+        match self.writes.borrow().get(&(table, key)) {
+            Some(value) => Ok(value.clone()),
+            None => (self.read)(table, key),
+        }
+    }
+
+    fn insert(
+        &self,
+        table: usize,
+        key: Slice32,
+        value: &[u8],
+    ) -> Result<Option<Vec<u8>>, BackendError> {
+        let prev = self.get(table, key)?;
+        self.writes.borrow_mut().insert((table, key), Some(value.to_vec()));
+        Ok(prev)
+    }
+
+    fn remove(&self, table: usize, key: Slice32) -> Result<Option<Vec<u8>>, BackendError> {
+        let prev = self.get(table, key)?;
+        self.writes.borrow_mut().insert((table, key), None);
+        Ok(prev)
+    }
+}
+
+/// Table inside a storage backend, mapping 32-byte keys to binary values.
+pub trait Table: Send + Sync {
+    /// Returns number of entries in the table.
+    fn len(&self) -> Result<usize, BackendError>;
+
+    /// Detects whether the table has no entries.
+    fn is_empty(&self) -> Result<bool, BackendError> { self.len().map(|len| len == 0) }
+
+    /// Returns value stored under the key, if any.
+    fn get(&self, key: Slice32) -> Result<Option<Vec<u8>>, BackendError>;
+
+    /// Checks whether some value is stored under the key.
+    fn contains_key(&self, key: Slice32) -> Result<bool, BackendError> {
+        self.get(key).map(|value| value.is_some())
+    }
+
+    /// Stores value under the key, returning the value which was previously
+    /// stored.
+    fn insert(&self, key: Slice32, value: &[u8]) -> Result<Option<Vec<u8>>, BackendError>;
+
+    /// Removes value stored under the key, returning it.
+    fn remove(&self, key: Slice32) -> Result<Option<Vec<u8>>, BackendError>;
+
+    /// Removes entry under a key returned by [`Table::range`], which may be of
+    /// non-standard length in a corrupted table.
+    fn remove_raw(&self, key: &[u8]) -> Result<(), BackendError> {
+        if let Some(key) = Slice32::from_slice(key) {
+            self.remove(key)?;
+        }
+        Ok(())
+    }
+
+    /// Stores `new` value under the key only if the value currently stored
+    /// equals `old`, where `old` of `None` requires the key to be absent and
+    /// `new` of `None` removes the key. If the values differ, returns the
+    /// current value as an inner error.
+    ///
+    /// Default implementation reads the current value and writes the new one
+    /// in two separate steps, so it is not atomic with respect to concurrent
+    /// writes.
+    fn compare_and_swap(
+        &self,
+        key: Slice32,
+        old: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<Result<(), Option<Vec<u8>>>, BackendError> {
+        let current = self.get(key)?;
+        if current.as_deref() != old {
+            return Ok(Err(current));
+        }
+        match new {
+            Some(new) => self.insert(key, new)?,
+            None => self.remove(key)?,
+        };
+        Ok(Ok(()))
+    }
+
+    /// Removes all entries from the table.
+    fn clear(&self) -> Result<(), BackendError>;
+
+    /// Stores all the provided values atomically: either all of them are
+    /// written or none.
+    fn apply_batch(&self, items: Vec<(Slice32, Vec<u8>)>) -> Result<(), BackendError>;
+
+    /// Iterates over entries with keys inside the range, in ascending key
+    /// order.
+    fn range<'me>(
+        &'me self,
+        start: Bound<Slice32>,
+        end: Bound<Slice32>,
+    ) -> Box<dyn Iterator<Item = Result<Entry, BackendError>> + 'me>;
+
+    /// Iterates over all entries of the table as they were at the moment of
+    /// the call, not affected by concurrent writes.
+    ///
+    /// Default implementation iterates over the whole key range, which is
+    /// consistent only if the backend iterators are.
+    fn snapshot<'me>(&'me self) -> Box<dyn Iterator<Item = Result<Entry, BackendError>> + 'me> {
+        self.range(Bound::Unbounded, Bound::Unbounded)
+    }
+
+    /// Makes all data written to the table durable.
+    fn flush(&self) -> Result<(), BackendError>;
+}
+
+/// Opens storage backend specified by the configuration.
+pub fn open(config: &Config) -> Result<Box<dyn StorageBackend>, LaunchError> {
+    Ok(match config.backend {
+        BackendType::Sled => Box::new(SledBackend::open(&config.data_dir)?),
+        BackendType::Memory => Box::new(MemoryBackend::new()),
+        BackendType::Fs => Box::new(FsBackend::open(&config.data_dir, config.durability)?),
+    })
+}
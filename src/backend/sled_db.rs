@@ -0,0 +1,251 @@
+// Storage daemon (stored): microservice frontend for different storage backends
+// used in LNP/BP nodes.
+//
+// Written in 2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// Copyright (C) 2022 by LNP/BP Standards Association, Switzerland.
+//
+// You should have received a copy of the MIT License along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+use std::collections::BTreeSet;
+use std::ops::Bound;
+use std::path::Path;
+
+use amplify::Slice32;
+use sled::transaction::{
+    ConflictableTransactionError, TransactionError, Transactional, TransactionalTree,
+    UnabortableTransactionError,
+};
+
+use super::{Entry, StorageBackend, Table, TxFn, TxView};
+use crate::{BackendError, DaemonError, STORED_STORAGE_FILE};
+
+/// Name of the tree which is always present in sled database and is not used
+/// for keeping tables.
+const SLED_DEFAULT_TREE: &[u8] = b"__sled__default";
+
+/// Storage backend using embedded sled database.
+pub struct SledBackend {
+    db: sled::Db,
+}
+
+impl SledBackend {
+    pub fn open(data_dir: &Path) -> Result<Self, BackendError> {
+        let db_path = data_dir.join(STORED_STORAGE_FILE);
+        debug!("Opening sled database at {}", db_path.display());
+        let db = sled::open(db_path)?;
+        Ok(SledBackend { db })
+    }
+}
+
+impl StorageBackend for SledBackend {
+    fn open_table(&self, name: &str) -> Result<Box<dyn Table>, BackendError> {
+        Ok(Box::new(self.db.open_tree(name)?))
+    }
+
+    fn table_names(&self) -> Result<BTreeSet<String>, BackendError> {
+        Ok(self
+            .db
+            .tree_names()
+            .into_iter()
+            .filter(|name| name.as_ref() != SLED_DEFAULT_TREE)
+            .map(|name| String::from_utf8_lossy(&name).into_owned())
+            .collect())
+    }
+
+    fn drop_table(&self, name: &str) -> Result<bool, BackendError> { Ok(self.db.drop_tree(name)?) }
+
+    fn transaction(&self, tables: &[&str], tx: &TxFn) -> Result<(), DaemonError> {
+        let trees = tables
+            .iter()
+            .map(|name| self.db.open_tree(name))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(BackendError::from)?;
+        trees[..]
+            .transaction(|views| {
+                tx(&SledTx(views)).map_err(|err| match err {
+                    // Sled retries conflicting transactions
+                    DaemonError::Database(BackendError::Conflict) => {
+                        ConflictableTransactionError::Conflict
+                    }
+                    err => ConflictableTransactionError::Abort(err),
+                })
+            })
+            .map_err(|err| match err {
+                TransactionError::Abort(err) => err,
+                TransactionError::Storage(err) => BackendError::from(err).into(),
+            })
+    }
+
+    fn flush(&self) -> Result<(), BackendError> {
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+/// View of sled trees participating in a transaction.
+struct SledTx<'view>(&'view [TransactionalTree]);
+
+impl From<UnabortableTransactionError> for BackendError {
+    fn from(err: UnabortableTransactionError) -> Self {
+        match err {
+            UnabortableTransactionError::Conflict => BackendError::Conflict,
+            UnabortableTransactionError::Storage(err) => BackendError::Sled(err),
+        }
+    }
+}
+
+impl<'view> TxView for SledTx<'view> {
+    fn get(&self, table: usize, key: Slice32) -> Result<Option<Vec<u8>>, BackendError> {
+        Ok(self.0[table].get(key)?.map(|ivec| ivec.to_vec()))
+    }
+
+    fn insert(
+        &self,
+        table: usize,
+        key: Slice32,
+        value: &[u8],
+    ) -> Result<Option<Vec<u8>>, BackendError> {
+        Ok(self.0[table].insert(&key[..], value)?.map(|ivec| ivec.to_vec()))
+    }
+
+    fn remove(&self, table: usize, key: Slice32) -> Result<Option<Vec<u8>>, BackendError> {
+        Ok(self.0[table].remove(&key[..])?.map(|ivec| ivec.to_vec()))
+    }
+}
+
+impl Table for sled::Tree {
+    fn len(&self) -> Result<usize, BackendError> { Ok(sled::Tree::len(self)) }
+
+    fn get(&self, key: Slice32) -> Result<Option<Vec<u8>>, BackendError> {
+        Ok(sled::Tree::get(self, key)?.map(|ivec| ivec.to_vec()))
+    }
+
+    fn contains_key(&self, key: Slice32) -> Result<bool, BackendError> {
+        Ok(sled::Tree::contains_key(self, key)?)
+    }
+
+    fn insert(&self, key: Slice32, value: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
+        Ok(sled::Tree::insert(self, key, value)?.map(|ivec| ivec.to_vec()))
+    }
+
+    fn remove(&self, key: Slice32) -> Result<Option<Vec<u8>>, BackendError> {
+        Ok(sled::Tree::remove(self, key)?.map(|ivec| ivec.to_vec()))
+    }
+
+    fn remove_raw(&self, key: &[u8]) -> Result<(), BackendError> {
+        sled::Tree::remove(self, key)?;
+        Ok(())
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: Slice32,
+        old: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<Result<(), Option<Vec<u8>>>, BackendError> {
+        Ok(sled::Tree::compare_and_swap(self, key, old, new)?
+            .map_err(|err| err.current.map(|ivec| ivec.to_vec())))
+    }
+
+    fn clear(&self) -> Result<(), BackendError> {
+        sled::Tree::clear(self)?;
+        Ok(())
+    }
+
+    fn apply_batch(&self, items: Vec<(Slice32, Vec<u8>)>) -> Result<(), BackendError> {
+        let mut batch = sled::Batch::default();
+        for (key, value) in items {
+            batch.insert(&key[..], value);
+        }
+        sled::Tree::apply_batch(self, batch)?;
+        Ok(())
+    }
+
+    fn range<'me>(
+        &'me self,
+        start: Bound<Slice32>,
+        end: Bound<Slice32>,
+    ) -> Box<dyn Iterator<Item = Result<Entry, BackendError>> + 'me> {
+        Box::new(sled::Tree::range(self, (start, end)).map(|res| {
+            res.map(|(key, value)| (key.to_vec(), value.to_vec())).map_err(BackendError::from)
+        }))
+    }
+
+    fn flush(&self) -> Result<(), BackendError> {
+        sled::Tree::flush(self)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+
+    use amplify::Slice32;
+
+    use super::SledBackend;
+    use crate::backend::StorageBackend;
+    use crate::{BackendError, DaemonError};
+
+    fn key(byte: u8) -> Slice32 { Slice32::from([byte; 32]) }
+
+    #[test]
+    fn transaction() {
+        let dir = std::env::temp_dir().join(format!("stored-sled-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let backend = SledBackend::open(&dir).unwrap();
+        let chunks = backend.open_table("chunks").unwrap();
+        let index = backend.open_table("index").unwrap();
+        chunks.insert(key(1), b"one").unwrap();
+
+        backend
+            .transaction(&["chunks", "index"], &|view| {
+                view.insert(0, key(2), b"two")?;
+                assert_eq!(view.get(0, key(2))?, Some(b"two".to_vec()));
+                assert_eq!(view.remove(0, key(1))?, Some(b"one".to_vec()));
+                view.insert(1, key(2), b"index")?;
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(chunks.get(key(1)).unwrap(), None);
+        assert_eq!(chunks.get(key(2)).unwrap(), Some(b"two".to_vec()));
+        assert_eq!(index.get(key(2)).unwrap(), Some(b"index".to_vec()));
+
+        // Failed transaction leaves all tables intact
+        let err = backend
+            .transaction(&["chunks", "index"], &|view| {
+                view.insert(1, key(3), b"index")?;
+                view.remove(0, key(2))?;
+                Err(DaemonError::UnknownTable(s!("none")))
+            })
+            .unwrap_err();
+        assert_eq!(err, DaemonError::UnknownTable(s!("none")));
+        assert_eq!(chunks.get(key(2)).unwrap(), Some(b"two".to_vec()));
+        assert_eq!(index.len().unwrap(), 1);
+
+        // Conflicting transaction is retried and only the last attempt is
+        // applied
+        let attempts = Cell::new(0);
+        backend
+            .transaction(&["chunks", "index"], &|view| {
+                attempts.set(attempts.get() + 1);
+                view.insert(0, key(attempts.get()), b"attempt")?;
+                if attempts.get() == 1 {
+                    return Err(BackendError::Conflict.into());
+                }
+                view.insert(1, key(3), b"index")?;
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(attempts.get(), 2);
+        assert_eq!(chunks.get(key(1)).unwrap(), None);
+        assert_eq!(chunks.get(key(2)).unwrap(), Some(b"attempt".to_vec()));
+        assert_eq!(index.get(key(3)).unwrap(), Some(b"index".to_vec()));
+
+        drop(backend);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,423 @@
+// Storage daemon (stored): microservice frontend for different storage backends
+// used in LNP/BP nodes.
+//
+// Written in 2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// Copyright (C) 2022 by LNP/BP Standards Association, Switzerland.
+//
+// You should have received a copy of the MIT License along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Portable backup archives keeping snapshot of all tables in the storage.
+//!
+//! Archive starts with [`ARCHIVE_MAGIC`] and little-endian `u16` format
+//! version, followed by a sequence of records:
+//! - `0x01`, `u16` length and UTF-8 name of a table, starting the table;
+//! - `0x02`, `u32` length and bytes of a key, followed by `u32` length and bytes of a value, adding
+//!   an entry to the last started table;
+//! - `0x00`, ending the archive.
+//!
+//! All lengths are little-endian. The archive is terminated by SHA256 hash of
+//! all the preceding data.
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::mem;
+use std::path::Path;
+
+use amplify::Slice32;
+use bitcoin_hashes::{sha256, Hash, HashEngine};
+
+use crate::backend::{self, BackendType, Table};
+use crate::{
+    BackendError, BackupError, Config, DaemonError, Durability, LaunchError, STORED_FS_DIR,
+    STORED_STORAGE_FILE,
+};
+
+/// Magic bytes starting each backup archive
+pub const ARCHIVE_MAGIC: [u8; 8] = *b"STOREDBK";
+
+/// Version of the archive format written by the daemon
+pub const ARCHIVE_VERSION: u16 = 1;
+
+/// Length of the magic bytes followed by the format version
+const HEADER_LEN: usize = ARCHIVE_MAGIC.len() + 2;
+
+const TAG_END: u8 = 0x00;
+const TAG_TABLE: u8 = 0x01;
+const TAG_ENTRY: u8 = 0x02;
+
+/// Directory inside the data directory where the storage is rebuilt before
+/// replacing the existing one
+const RESTORE_DIR: &str = "restore.tmp";
+
+/// Number of entries written into the restored storage at once
+const RESTORE_BATCH: usize = 1024;
+
+struct ArchiveWriter {
+    file: BufWriter<File>,
+    engine: sha256::HashEngine,
+}
+
+impl ArchiveWriter {
+    fn write(&mut self, data: &[u8]) -> Result<(), BackupError> {
+        self.engine.input(data);
+        self.file.write_all(data)?;
+        Ok(())
+    }
+
+    fn write_data(&mut self, data: &[u8]) -> Result<(), BackupError> {
+        let len = u32::try_from(data.len()).map_err(|_| BackupError::Malformed)?;
+        self.write(&len.to_le_bytes())?;
+        self.write(data)
+    }
+
+    fn finish(mut self) -> Result<(), BackupError> {
+        let checksum = sha256::Hash::from_engine(self.engine);
+        self.file.write_all(&checksum[..])?;
+        let file = self.file.into_inner().map_err(io::IntoInnerError::into_error)?;
+        file.sync_all()?;
+        Ok(())
+    }
+}
+
+/// Writes all entries of the tables into archive at `path`, returning number
+/// of the written entries.
+///
+/// The archive is written into a temporary file which replaces the one at
+/// `path` only once complete, so a failed backup does not leave a truncated
+/// archive.
+pub fn write<'t>(
+    path: &Path,
+    tables: impl IntoIterator<Item = (&'t str, &'t dyn Table)>,
+) -> Result<u64, DaemonError> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let mut writer = ArchiveWriter {
+        file: BufWriter::new(File::create(&tmp).map_err(BackupError::from)?),
+        engine: sha256::Hash::engine(),
+    };
+    writer.write(&ARCHIVE_MAGIC)?;
+    writer.write(&ARCHIVE_VERSION.to_le_bytes())?;
+    let mut entries = 0u64;
+    for (name, table) in tables {
+        let len = u16::try_from(name.len()).map_err(|_| BackupError::Malformed)?;
+        writer.write(&[TAG_TABLE])?;
+        writer.write(&len.to_le_bytes())?;
+        writer.write(name.as_bytes())?;
+        for entry in table.snapshot() {
+            let (key, value) = entry?;
+            writer.write(&[TAG_ENTRY])?;
+            writer.write_data(&key)?;
+            writer.write_data(&value)?;
+            entries += 1;
+        }
+    }
+    writer.write(&[TAG_END])?;
+    writer.finish()?;
+    fs::rename(&tmp, path).map_err(BackupError::from)?;
+    Ok(entries)
+}
+
+/// Record read from an archive
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Record {
+    /// Starts the table with the given name
+    Table(String),
+
+    /// Entry of the last started table
+    Entry(Vec<u8>, Vec<u8>),
+}
+
+/// Iterator over records of an archive, reading them from the file one by one
+pub struct Records {
+    /// Archive data between the header and the checksum
+    data: io::Take<BufReader<File>>,
+    table_started: bool,
+    done: bool,
+}
+
+impl Records {
+    /// Opens archive at `path`, checking its header but not the checksum.
+    fn open(path: &Path) -> Result<Self, BackupError> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len();
+        let mut file = BufReader::new(file);
+        let mut header = [0u8; HEADER_LEN];
+        match file.read_exact(&mut header) {
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err(BackupError::NotArchive)
+            }
+            res => res?,
+        }
+        if header[..ARCHIVE_MAGIC.len()] != ARCHIVE_MAGIC {
+            return Err(BackupError::NotArchive);
+        }
+        let version = u16::from_le_bytes([header[HEADER_LEN - 2], header[HEADER_LEN - 1]]);
+        if version != ARCHIVE_VERSION {
+            return Err(BackupError::UnsupportedVersion(version));
+        }
+        let data_len = len
+            .checked_sub((HEADER_LEN + sha256::Hash::LEN) as u64)
+            .filter(|len| *len > 0)
+            .ok_or(BackupError::Malformed)?;
+        Ok(Records {
+            data: file.take(data_len),
+            table_started: false,
+            done: false,
+        })
+    }
+
+    fn take(&mut self, len: usize) -> Result<Vec<u8>, BackupError> {
+        // Length is checked before allocating, so a malformed length can't
+        // exhaust the memory
+        if len as u64 > self.data.limit() {
+            return Err(BackupError::Malformed);
+        }
+        let mut data = vec![0u8; len];
+        self.data.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    fn read_data(&mut self) -> Result<Vec<u8>, BackupError> {
+        let len = self.take(4)?;
+        let len = u32::from_le_bytes([len[0], len[1], len[2], len[3]]);
+        self.take(len as usize)
+    }
+
+    fn read_record(&mut self) -> Result<Option<Record>, BackupError> {
+        match self.take(1)?[0] {
+            TAG_END if self.data.limit() == 0 => Ok(None),
+            TAG_TABLE => {
+                let len = self.take(2)?;
+                let name = self.take(u16::from_le_bytes([len[0], len[1]]) as usize)?;
+                let name = String::from_utf8(name).map_err(|_| BackupError::Malformed)?;
+                self.table_started = true;
+                Ok(Some(Record::Table(name)))
+            }
+            TAG_ENTRY if self.table_started => {
+                let key = self.read_data()?;
+                let value = self.read_data()?;
+                Ok(Some(Record::Entry(key, value)))
+            }
+            _ => Err(BackupError::Malformed),
+        }
+    }
+}
+
+impl Iterator for Records {
+    type Item = Result<Record, BackupError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let record = self.read_record().transpose();
+        // Reading stops at the archive end or at the first error
+        self.done = !matches!(record, Some(Ok(_)));
+        record
+    }
+}
+
+/// Checks that the archive data match the checksum at the end of the archive.
+fn check(path: &Path) -> Result<(), BackupError> {
+    let file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let mut file = BufReader::new(file);
+    let data_len = len.checked_sub(sha256::Hash::LEN as u64).ok_or(BackupError::Malformed)?;
+    let mut data = (&mut file).take(data_len);
+    let mut engine = sha256::Hash::engine();
+    let mut buf = [0u8; 8192];
+    loop {
+        match data.read(&mut buf)? {
+            0 => break,
+            read => engine.input(&buf[..read]),
+        }
+    }
+    let mut checksum = [0u8; sha256::Hash::LEN];
+    file.read_exact(&mut checksum)?;
+    if sha256::Hash::from_engine(engine)[..] != checksum {
+        return Err(BackupError::ChecksumMismatch);
+    }
+    Ok(())
+}
+
+/// Reads archive at `path`. The header and the checksum are verified before
+/// the records are returned; the records are read from the file while
+/// iterating, so the archive is never loaded into memory as a whole.
+pub fn read(path: &Path) -> Result<Records, BackupError> {
+    let records = Records::open(path)?;
+    check(path)?;
+    Ok(records)
+}
+
+/// Rebuilds storage in the data directory from the archive at `path`,
+/// returning number of the restored entries.
+///
+/// The storage is first built in a separate directory and compared with the
+/// archive; only then it replaces the existing storage, which is kept in the
+/// data directory with `.old` suffix.
+pub fn restore(config: &Config, path: &Path) -> Result<u64, LaunchError> {
+    let target = match config.backend {
+        BackendType::Sled => STORED_STORAGE_FILE,
+        BackendType::Fs => STORED_FS_DIR,
+        BackendType::Memory => return Err(BackupError::UnsupportedBackend(config.backend).into()),
+    };
+    let records = read(path)?;
+
+    // Both sled and file backends lock the storage, so opening it fails if it
+    // is used by a running daemon, whose files must not be replaced. Storage
+    // which does not exist yet is not opened, since that would create it.
+    if config.data_dir.join(target).exists() {
+        drop(backend::open(config)?);
+    }
+
+    let mut staging = config.clone();
+    staging.data_dir = config.data_dir.join(RESTORE_DIR);
+    // Restored tables are synced to disk once each of them is rebuilt
+    staging.durability = Durability::Group;
+    if staging.data_dir.exists() {
+        fs::remove_dir_all(&staging.data_dir).map_err(BackupError::from)?;
+    }
+    let entries = rebuild(&staging, records)?;
+    // The checksum is already verified, so the archive is read once more only
+    // to compare its entries with the restored ones
+    verify(&staging, Records::open(path)?)?;
+
+    replace(&staging.data_dir.join(target), &config.data_dir.join(target))?;
+    fs::remove_dir_all(&staging.data_dir).map_err(BackupError::from)?;
+    Ok(entries)
+}
+
+fn rebuild(config: &Config, records: Records) -> Result<u64, LaunchError> {
+    let db = backend::open(config)?;
+    let mut table = None::<Box<dyn Table>>;
+    let mut batch = Vec::with_capacity(RESTORE_BATCH);
+    let mut entries = 0u64;
+    for record in records {
+        match record? {
+            Record::Table(name) => {
+                if let Some(table) = table {
+                    table.apply_batch(mem::take(&mut batch))?;
+                    table.flush()?;
+                }
+                table = Some(db.open_table(&name)?);
+            }
+            Record::Entry(key, value) => {
+                let key = Slice32::from_slice(&key).ok_or(BackendError::InvalidKey(key.len()))?;
+                batch.push((key, value));
+                entries += 1;
+                if batch.len() == RESTORE_BATCH {
+                    let table = table.as_ref().expect("entries follow a table");
+                    table
+                        .apply_batch(mem::replace(&mut batch, Vec::with_capacity(RESTORE_BATCH)))?;
+                }
+            }
+        }
+    }
+    if let Some(table) = table {
+        table.apply_batch(batch)?;
+        table.flush()?;
+    }
+    db.flush()?;
+    Ok(entries)
+}
+
+/// Reopens the rebuilt storage and checks that it holds exactly the data from
+/// the archive.
+fn verify(config: &Config, records: Records) -> Result<(), LaunchError> {
+    let db = backend::open(config)?;
+    // Restored table with the number of its entries found in the archive
+    let mut current = None::<(String, Box<dyn Table>, usize)>;
+    for record in records {
+        match record? {
+            Record::Table(name) => {
+                if let Some((name, table, count)) = current.take() {
+                    verify_count(name, table.as_ref(), count)?;
+                }
+                let table = db.open_table(&name)?;
+                current = Some((name, table, 0));
+            }
+            Record::Entry(key, value) => {
+                let (name, table, count) = current.as_mut().expect("entries follow a table");
+                let key = Slice32::from_slice(&key).ok_or(BackendError::InvalidKey(key.len()))?;
+                if table.get(key)?.as_ref() != Some(&value) {
+                    return Err(BackupError::RestoreMismatch(name.clone()).into());
+                }
+                *count += 1;
+            }
+        }
+    }
+    if let Some((name, table, count)) = current {
+        verify_count(name, table.as_ref(), count)?;
+    }
+    Ok(())
+}
+
+/// Checks that the restored table has no entries other than the ones from the
+/// archive.
+fn verify_count(name: String, table: &dyn Table, count: usize) -> Result<(), LaunchError> {
+    if table.len()? != count {
+        return Err(BackupError::RestoreMismatch(name).into());
+    }
+    Ok(())
+}
+
+/// Moves storage from `from` into `to`, keeping the replaced storage with
+/// `.old` suffix.
+fn replace(from: &Path, to: &Path) -> Result<(), BackupError> {
+    if to.exists() {
+        let mut old = to.as_os_str().to_owned();
+        old.push(".old");
+        let old = Path::new(&old);
+        if old.exists() {
+            fs::remove_dir_all(old)?;
+        }
+        fs::rename(to, old)?;
+        info!("Replaced storage is kept at {}", old.display());
+    }
+    fs::rename(from, to)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use amplify::Slice32;
+
+    use super::{read, write, Record};
+    use crate::backend::{MemoryBackend, StorageBackend};
+    use crate::BackupError;
+
+    #[test]
+    fn archive_roundtrip() {
+        let backend = MemoryBackend::new();
+        let chunks = backend.open_table("chunks").unwrap();
+        let empty = backend.open_table("empty").unwrap();
+        chunks.insert(Slice32::from([1u8; 32]), b"one").unwrap();
+        chunks.insert(Slice32::from([2u8; 32]), b"two").unwrap();
+
+        let path = std::env::temp_dir().join(format!("stored-backup-{}", std::process::id()));
+        let tables = [("chunks", chunks.as_ref()), ("empty", empty.as_ref())];
+        assert_eq!(write(&path, tables).unwrap(), 2);
+
+        let records = read(&path).unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(records, vec![
+            Record::Table(s!("chunks")),
+            Record::Entry(vec![1u8; 32], b"one".to_vec()),
+            Record::Entry(vec![2u8; 32], b"two".to_vec()),
+            Record::Table(s!("empty")),
+        ]);
+
+        let mut data = fs::read(&path).unwrap();
+        data[20] ^= 0xFF;
+        fs::write(&path, &data).unwrap();
+        assert_eq!(read(&path).err(), Some(BackupError::ChecksumMismatch));
+        data.truncate(9);
+        fs::write(&path, &data).unwrap();
+        assert_eq!(read(&path).err(), Some(BackupError::NotArchive));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -15,42 +15,266 @@
 
 #[macro_use]
 extern crate log;
+#[macro_use]
+extern crate configure_me;
+
+mod internal {
+    #![allow(unused, clippy::all)]
+    include!(concat!(env!("OUT_DIR"), "/configure_me_config.rs"));
+}
+
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use std::{fs, process};
 
 use clap::Parser;
+use internet2::addr::NodeId;
 use microservices::error::BootstrapError;
 use microservices::shell::LogLevel;
-use stored::opts::Opts;
-use stored::{Config, LaunchError};
+use store_rpc::STORED_RPC_ENDPOINT;
+use stored::backend::BackendType;
+use stored::fsck::QUARANTINE_TABLE;
+use stored::opts::{
+    Command, Opts, STORED_CONFIG, STORED_DATA_DIR, STORED_FLUSH_INTERVAL, STORED_FLUSH_WRITES,
+    STORED_LOCAL_RPC_ENDPOINT, STORED_THREADS,
+};
+use stored::{Acl, Config, Durability, LaunchError};
+
+use self::internal::ResultExt;
 
 fn main() -> Result<(), BootstrapError<LaunchError>> {
-    println!("stored: storage microservice");
+    eprintln!("stored: storage microservice");
 
     let opts = Opts::parse();
     LogLevel::from_verbosity_flag_count(opts.verbose).apply();
     trace!("Command-line arguments: {:?}", &opts);
 
-    let mut config = Config {
-        data_dir: opts.data_dir,
-        rpc_endpoint: opts.rpc_endpoint,
-        verbose: opts.verbose,
-        databases: opts.tables.iter().cloned().collect(),
-    };
+    let print_config = opts.print_config;
+    let command = opts.command.clone();
+    let restore = opts.restore.clone();
+    let mut config = load_config(opts);
     trace!("Daemon configuration: {:?}", config);
     config.process();
     trace!("Processed configuration: {:?}", config);
-    debug!("CTL RPC socket {}", config.rpc_endpoint);
 
-    /*
-    use self::internal::ResultExt;
-    let (config_from_file, _) =
-        internal::Config::custom_args_and_optional_files(std::iter::empty::<
-            &str,
-        >())
-        .unwrap_or_exit();
-     */
+    if print_config {
+        print!("{}", config_toml(&config));
+        return Ok(());
+    }
+
+    if let Err(err) = fs::create_dir_all(&config.data_dir) {
+        exit_with_error(format!(
+            "unable to create data directory {}: {}",
+            config.data_dir.display(),
+            err
+        ));
+    }
+
+    if let Some(ref archive) = restore {
+        let entries = stored::backup::restore(&config, archive)?;
+        eprintln!("Restored {} entries from {}", entries, archive.display());
+    }
+
+    if let Some(Command::Fsck { quarantine }) = command {
+        return fsck(&config, quarantine);
+    }
+
+    debug!("CTL RPC socket {}", config.rpc_endpoint);
 
     debug!("Starting runtime ...");
-    stored::service::run(config).expect("running stored runtime");
+    stored::service::run(config)
+}
+
+/// Checks integrity of the storage, printing corrupted entries. Exit code
+/// follows fsck(8) convention.
+fn fsck(config: &Config, quarantine: bool) -> Result<(), BootstrapError<LaunchError>> {
+    let reports = stored::fsck::run(config, quarantine)?;
+    let mut corrupted = 0usize;
+    for (table, report) in reports {
+        eprintln!("Table '{}': {} entries, {} corrupted", table, report.checked, report.bad.len());
+        for entry in &report.bad {
+            println!("{} {}", table, entry);
+        }
+        corrupted += report.bad.len();
+    }
+    match (corrupted, quarantine) {
+        (0, _) => Ok(()),
+        (_, true) => {
+            eprintln!("{} corrupted entries moved to '{}'", corrupted, QUARANTINE_TABLE);
+            process::exit(1)
+        }
+        (_, false) => process::exit(4),
+    }
+}
+
+/// Reads configuration file and combines it with command-line arguments and
+/// environment variables. The precedence of values is the following:
+/// command-line arguments, environment variables, configuration file and
+/// default values.
+fn load_config(opts: Opts) -> Config {
+    let data_dir = opts.data_dir.clone().unwrap_or_else(|| PathBuf::from(STORED_DATA_DIR));
+    let config_file = match opts.config {
+        Some(ref path) if !path.is_file() => {
+            exit_with_error(format!("configuration file {} is not found", path.display()))
+        }
+        Some(ref path) => path.clone(),
+        None => {
+            let path = STORED_CONFIG.replace("{data_dir}", &data_dir.to_string_lossy());
+            PathBuf::from(shellexpand::tilde(&path).to_string())
+        }
+    };
+    debug!("Reading configuration from {}", config_file.display());
+    let (file, _) = internal::Config::custom_args_and_optional_files(
+        std::iter::empty::<&str>(),
+        Some(&config_file),
+    )
+    .unwrap_or_exit();
+
+    let pub_endpoint = opts.pub_endpoint.or_else(|| {
+        file.pub_endpoint
+            .as_deref()
+            .map(|addr| parse_config_value(addr, "notification endpoint", &config_file))
+    });
+    let secure_endpoint = opts.secure.or_else(|| {
+        file.secure_endpoint
+            .as_deref()
+            .map(|addr| parse_config_value(addr, "secure endpoint", &config_file))
+    });
+    let rpc_endpoint = opts.rpc_endpoint.unwrap_or_else(|| {
+        // Unencrypted RPC must not bypass authentication of encrypted sessions
+        let default = match secure_endpoint {
+            None => STORED_RPC_ENDPOINT,
+            Some(_) => STORED_LOCAL_RPC_ENDPOINT,
+        };
+        let addr = file.rpc_endpoint.as_deref().unwrap_or(default);
+        parse_config_value(addr, "RPC endpoint", &config_file)
+    });
+    let allowed_clients = if opts.allowed_clients.is_empty() {
+        file.allowed_clients
+            .unwrap_or_default()
+            .iter()
+            .map(|key| parse_config_value::<NodeId>(key, "client key", &config_file))
+            .collect()
+    } else {
+        opts.allowed_clients.into_iter().collect()
+    };
+    let acl = if opts.acl.is_empty() {
+        file.acl
+            .unwrap_or_default()
+            .iter()
+            .map(|rule| parse_config_value(rule, "ACL rule", &config_file))
+            .collect()
+    } else {
+        opts.acl
+    };
+    let backend = opts.backend.unwrap_or_else(|| match file.backend {
+        None => BackendType::Sled,
+        Some(ref name) => parse_config_value(name, "storage backend", &config_file),
+    });
+    let durability = opts.durability.unwrap_or_else(|| match file.durability {
+        None => Durability::Sync,
+        Some(ref mode) => parse_config_value(mode, "durability mode", &config_file),
+    });
+    let tables = if opts.tables.is_empty() { file.tables.unwrap_or_default() } else { opts.tables };
+    let content_addressed = if opts.content_addressed.is_empty() {
+        file.content_addressed.unwrap_or_default()
+    } else {
+        opts.content_addressed
+    };
+
+    Config {
+        data_dir: opts.data_dir.or(file.data_dir).unwrap_or(data_dir),
+        rpc_endpoint,
+        pub_endpoint,
+        secure_endpoint,
+        allowed_clients,
+        acl: Acl::with(acl),
+        verbose: opts.verbose,
+        backend,
+        threads: opts.threads.or(file.threads).unwrap_or(STORED_THREADS),
+        durability,
+        flush_interval: Duration::from_millis(
+            opts.flush_interval.or(file.flush_interval).unwrap_or(STORED_FLUSH_INTERVAL),
+        ),
+        flush_writes: opts.flush_writes.or(file.flush_writes).unwrap_or(STORED_FLUSH_WRITES),
+        databases: tables.into_iter().collect(),
+        content_addressed: content_addressed.into_iter().collect(),
+    }
+}
+
+/// Renders configuration in the format of the configuration file, so that the
+/// output can be used as `stored.toml`.
+fn config_toml(config: &Config) -> String {
+    let mut tables = config.databases.iter().collect::<Vec<_>>();
+    tables.sort();
+    let mut params = vec![
+        ("data_dir", toml_string(config.data_dir.display())),
+        ("rpc_endpoint", toml_string(format!("{:#}", config.rpc_endpoint))),
+    ];
+    if let Some(ref endpoint) = config.pub_endpoint {
+        params.push(("pub_endpoint", toml_string(format!("{:#}", endpoint))));
+    }
+    if let Some(endpoint) = config.secure_endpoint {
+        params.push(("secure_endpoint", toml_string(endpoint)));
+    }
+    params.extend([
+        ("allowed_clients", toml_array(&config.allowed_clients)),
+        ("acl", toml_array(config.acl.rules())),
+        ("backend", toml_string(config.backend)),
+        ("threads", config.threads.to_string()),
+        ("durability", toml_string(config.durability)),
+        ("flush_interval", config.flush_interval.as_millis().to_string()),
+        ("flush_writes", config.flush_writes.to_string()),
+        ("content_addressed", toml_array(&config.content_addressed)),
+        ("tables", toml_array(tables)),
+    ]);
+    params.into_iter().map(|(name, value)| format!("{} = {}\n", name, value)).collect()
+}
+
+/// Formats value as a TOML basic string.
+fn toml_string(value: impl Display) -> String {
+    let mut quoted = String::from('"');
+    for c in value.to_string().chars() {
+        match c {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04X}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Formats values as a TOML array of strings.
+fn toml_array<T: Display>(values: impl IntoIterator<Item = T>) -> String {
+    let items = values.into_iter().map(toml_string).collect::<Vec<_>>();
+    format!("[{}]", items.join(", "))
+}
+
+/// Parses value read from the configuration file, terminating the process if
+/// the value is invalid.
+fn parse_config_value<T>(value: &str, what: &str, config_file: &Path) -> T
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    T::from_str(value).unwrap_or_else(|err| {
+        exit_with_error(format!(
+            "invalid {} '{}' in {}: {}",
+            what,
+            value,
+            config_file.display(),
+            err
+        ))
+    })
+}
 
-    unreachable!()
+fn exit_with_error(message: String) -> ! {
+    eprintln!("Error: {}", message);
+    process::exit(1)
 }
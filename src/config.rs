@@ -9,11 +9,16 @@
 // You should have received a copy of the MIT License along with this software.
 // If not, see <https://opensource.org/licenses/MIT>.
 
-use std::collections::HashSet;
-use std::fs;
+use std::collections::{BTreeSet, HashSet};
+use std::iter;
+use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::time::Duration;
 
-use internet2::addr::ServiceAddr;
+use internet2::addr::{NodeId, ServiceAddr};
+
+use crate::backend::BackendType;
+use crate::{Acl, Durability};
 
 /// Final configuration resulting from data contained in config file environment
 /// variables and command-line options. For security reasons node key is kept
@@ -24,11 +29,41 @@ pub struct Config {
     /// ZMQ socket for RPC API
     pub rpc_endpoint: ServiceAddr,
 
+    /// ZMQ socket for publishing change notifications
+    pub pub_endpoint: Option<ServiceAddr>,
+
+    /// TCP address for encrypted client sessions
+    pub secure_endpoint: Option<SocketAddr>,
+
+    /// Clients allowed to use encrypted sessions
+    pub allowed_clients: BTreeSet<NodeId>,
+
+    /// Per-table access rules
+    pub acl: Acl,
+
     /// Data location
     pub data_dir: PathBuf,
 
+    /// Storage backend used for keeping the data
+    pub backend: BackendType,
+
+    /// Number of worker threads processing client requests
+    pub threads: usize,
+
+    /// Durability mode for the written data
+    pub durability: Durability,
+
+    /// Interval between flushes in `group` durability mode
+    pub flush_interval: Duration,
+
+    /// Number of writes triggering a flush in `group` durability mode
+    pub flush_writes: u64,
+
     pub databases: HashSet<String>,
 
+    /// Tables keeping chunks under their ids
+    pub content_addressed: BTreeSet<String>,
+
     /// Verbosity level
     pub verbose: u8,
 }
@@ -43,15 +78,25 @@ impl Config {
         self.process_dir(&mut data_dir);
         self.data_dir = PathBuf::from(data_dir);
 
-        fs::create_dir_all(&self.data_dir).expect("Unable to access data directory");
-
-        for dir in vec![&mut self.rpc_endpoint] {
+        for dir in iter::once(&mut self.rpc_endpoint).chain(self.pub_endpoint.as_mut()) {
             if let ServiceAddr::Ipc(ref mut path) = dir {
                 me.process_dir(path);
             }
         }
     }
 
+    /// Paths to the IPC socket files used by the daemon, which must be removed
+    /// on shutdown.
+    pub fn ipc_files(&self) -> Vec<PathBuf> {
+        iter::once(&self.rpc_endpoint)
+            .chain(self.pub_endpoint.as_ref())
+            .filter_map(|addr| match addr {
+                ServiceAddr::Ipc(path) => Some(PathBuf::from(path)),
+                _ => None,
+            })
+            .collect()
+    }
+
     pub fn process_dir(&self, path: &mut String) {
         *path = path.replace("{data_dir}", &self.data_dir.to_string_lossy());
         *path = shellexpand::tilde(path).to_string();
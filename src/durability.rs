@@ -0,0 +1,40 @@
+// Storage daemon (stored): microservice frontend for different storage backends
+// used in LNP/BP nodes.
+//
+// Written in 2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// Copyright (C) 2022 by LNP/BP Standards Association, Switzerland.
+//
+// You should have received a copy of the MIT License along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+use std::str::FromStr;
+
+/// Durability modes defining when the written data get flushed to disk
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug, Display)]
+#[display(lowercase)]
+pub enum Durability {
+    /// Each write is flushed to disk before the reply is sent to the client
+    Sync,
+
+    /// Writes are flushed in groups, each time the flush interval passes or
+    /// the number of not yet flushed writes reaches the limit
+    Group,
+
+    /// Flushing is left to the storage backend and the operating system
+    Os,
+}
+
+impl FromStr for Durability {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "sync" => Ok(Durability::Sync),
+            "group" => Ok(Durability::Group),
+            "os" => Ok(Durability::Os),
+            other => Err(format!("unknown durability mode '{}'", other)),
+        }
+    }
+}
@@ -9,15 +9,96 @@
 // You should have received a copy of the MIT License along with this software.
 // If not, see <https://opensource.org/licenses/MIT>.
 
+use std::io;
+
+use amplify::{IoError, Slice32, Wrapper};
+use internet2::addr::ServiceAddr;
 use microservices::rpc;
+use nix::errno::Errno;
 use store_rpc::{FailureCode, Reply};
+use storm::ChunkId;
+
+use crate::backend::BackendType;
+use crate::Permission;
+
+/// Errors happening inside a storage backend
+#[derive(Clone, PartialEq, Eq, Debug, Display, Error, From)]
+#[display(doc_comments)]
+pub enum BackendError {
+    /// sled database error: {0}
+    #[from]
+    Sled(sled::Error),
+
+    /// I/O error: {0}
+    #[from(std::io::Error)]
+    Io(IoError),
+
+    /// table name '{0}' is not supported by the storage backend
+    InvalidTableName(String),
+
+    /// table contains key of non-standard length {0}
+    InvalidKey(usize),
+
+    /// transaction conflicts with a concurrent one
+    Conflict,
+
+    /// storage at {0} is used by another process
+    Locked(String),
+}
+
+/// Errors happening while writing, reading or restoring backup archives
+#[derive(Clone, PartialEq, Eq, Debug, Display, Error, From)]
+#[display(doc_comments)]
+pub enum BackupError {
+    /// I/O error: {0}
+    #[from(std::io::Error)]
+    Io(IoError),
+
+    /// file is not a backup archive of the storage daemon
+    NotArchive,
+
+    /// backup archive version {0} is not supported
+    UnsupportedVersion(u16),
+
+    /// backup archive checksum does not match its data; the file is corrupted
+    ChecksumMismatch,
+
+    /// backup archive is malformed
+    Malformed,
+
+    /// backups can't be restored into {0} storage backend
+    UnsupportedBackend(BackendType),
+
+    /// restored table '{0}' differs from the one in the backup archive
+    RestoreMismatch(String),
+
+    /// backup path '{0}' must be a relative path inside the backup directory
+    InvalidPath(String),
+}
 
 #[derive(Clone, PartialEq, Eq, Debug, Display, Error, From)]
 #[display(doc_comments)]
 pub enum LaunchError {
     #[from]
     #[display(inner)]
-    Database(sled::Error),
+    Database(BackendError),
+
+    /// RPC socket error: {0}
+    #[from]
+    Zmq(zmq::Error),
+
+    /// unable to verify data integrity: {0}
+    #[from]
+    Verify(DaemonError),
+
+    /// unable to restore from backup: {0}
+    #[from]
+    Restore(BackupError),
+
+    /// unencrypted RPC endpoint {0} is reachable from the network while
+    /// encrypted sessions are enabled; bind it to a loopback address or an IPC
+    /// socket
+    ExposedRpc(ServiceAddr),
 }
 
 impl microservices::error::Error for LaunchError {}
@@ -27,27 +108,107 @@ impl microservices::error::Error for LaunchError {}
 pub enum DaemonError {
     #[from]
     #[display(inner)]
-    Database(sled::Error),
+    Database(BackendError),
 
     /// unknown database table '{0}'
     UnknownTable(String),
 
+    /// database table '{0}' already exists
+    TableExists(String),
+
+    /// value stored under key {key} is not a valid {expected}
+    ValueTypeMismatch {
+        key: Slice32,
+        expected: &'static str,
+    },
+
+    /// key {key} does not match id {chunk_id} of the chunk stored into a
+    /// content-addressed table
+    KeyMismatch { key: Slice32, chunk_id: ChunkId },
+
+    /// chunk stored under key {key} is corrupted and has id {chunk_id}
+    Corrupted { key: Slice32, chunk_id: ChunkId },
+
+    /// operation is not supported by content-addressed table '{0}'
+    ContentAddressed(String),
+
+    /// table '{0}' is not content-addressed
+    NotContentAddressed(String),
+
+    /// permission to {permission} table '{table}' is denied
+    PermissionDenied {
+        table: String,
+        permission: Permission,
+    },
+
     #[from]
     #[display(inner)]
     Encoding(strict_encoding::Error),
+
+    /// daemon is shutting down
+    ShuttingDown,
+
+    /// unable to initiate daemon shutdown: {0}
+    Shutdown(zmq::Error),
+
+    /// unable to write backup: {0}
+    #[from]
+    Backup(BackupError),
+
+    /// transaction is aborted due to failure of operation #{op}: {err}
+    TxAborted { op: usize, err: Box<DaemonError> },
 }
 
 impl microservices::error::Error for DaemonError {}
 
-impl From<DaemonError> for Reply {
-    fn from(err: DaemonError) -> Self {
-        let code = match err {
+impl DaemonError {
+    /// Returns failure code reported to the client for the error.
+    pub fn failure_code(&self) -> FailureCode {
+        match *self {
+            DaemonError::Database(BackendError::InvalidTableName(_))
+            | DaemonError::Database(BackendError::Sled(sled::Error::Unsupported(_))) => {
+                FailureCode::Unsupported
+            }
+            DaemonError::Database(BackendError::Io(ref err))
+                if *err.as_inner() == io::ErrorKind::PermissionDenied =>
+            {
+                FailureCode::ReadOnly
+            }
+            DaemonError::Database(BackendError::Sled(sled::Error::Io(ref err)))
+                if err.raw_os_error() == Some(Errno::ENOSPC as i32)
+                    || err.raw_os_error() == Some(Errno::EDQUOT as i32) =>
+            {
+                FailureCode::QuotaExceeded
+            }
             DaemonError::Database(_) => FailureCode::Database,
-            DaemonError::UnknownTable(_) => FailureCode::Database,
+            DaemonError::UnknownTable(_) => FailureCode::UnknownTable,
+            DaemonError::TableExists(_) => FailureCode::TableExists,
+            DaemonError::ValueTypeMismatch { .. } => FailureCode::ValueTypeMismatch,
+            DaemonError::KeyMismatch { .. } => FailureCode::IdMismatch,
+            DaemonError::Corrupted { .. } => FailureCode::Corrupted,
+            DaemonError::ContentAddressed(_) | DaemonError::NotContentAddressed(_) => {
+                FailureCode::Unsupported
+            }
+            DaemonError::PermissionDenied { .. } => FailureCode::PermissionDenied,
             DaemonError::Encoding(_) => FailureCode::Encoding,
-        };
+            DaemonError::ShuttingDown => FailureCode::ShuttingDown,
+            DaemonError::Shutdown(_) => FailureCode::ShutdownFailed,
+            DaemonError::Backup(BackupError::Io(ref err))
+                if *err.as_inner() == io::ErrorKind::PermissionDenied =>
+            {
+                FailureCode::ReadOnly
+            }
+            DaemonError::Backup(BackupError::InvalidPath(_)) => FailureCode::PermissionDenied,
+            DaemonError::Backup(_) => FailureCode::Database,
+            DaemonError::TxAborted { ref err, .. } => err.failure_code(),
+        }
+    }
+}
+
+impl From<DaemonError> for Reply {
+    fn from(err: DaemonError) -> Self {
         Reply::Failure(rpc::Failure {
-            code: code.into(),
+            code: err.failure_code().into(),
             info: err.to_string(),
         })
     }
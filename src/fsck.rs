@@ -0,0 +1,153 @@
+// Storage daemon (stored): microservice frontend for different storage backends
+// used in LNP/BP nodes.
+//
+// Written in 2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// Copyright (C) 2022 by LNP/BP Standards Association, Switzerland.
+//
+// You should have received a copy of the MIT License along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Integrity verification of the data kept in the storage.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
+
+use amplify::Slice32;
+use bitcoin_hashes::{sha256, Hash, HashEngine};
+use commit_verify::ConsensusCommit;
+use store_rpc::{BadEntry, Defect, VerifyReport};
+use storm::Chunk;
+use strict_encoding::{StrictDecode, StrictEncode};
+
+use crate::backend::Table;
+use crate::{backend, Config, DaemonError, LaunchError};
+
+/// Table keeping entries moved out of other tables by quarantine.
+///
+/// Entries are stored under SHA256 hash of the table name and the original
+/// key, and contain strict-encoded table name, original key and value.
+pub const QUARANTINE_TABLE: &str = "_corrupt";
+
+/// Walks all entries of the table, checking that keys are 32 bytes long, values
+/// are either chunks or sets of keys, and that chunks of content-addressed
+/// tables are stored under their ids.
+///
+/// If `quarantine` table is given, moves the entries which have failed the
+/// check into it.
+pub fn verify_table(
+    name: &str,
+    table: &dyn Table,
+    content_addressed: bool,
+    quarantine: Option<&dyn Table>,
+) -> Result<VerifyReport, DaemonError> {
+    let mut report = VerifyReport::default();
+    let mut corrupted = Vec::new();
+    for entry in table.range(Bound::Unbounded, Bound::Unbounded) {
+        let (key, value) = entry?;
+        report.checked += 1;
+        if let Some(defect) = check_entry(&key, &value, content_addressed) {
+            report.bad.push(BadEntry {
+                key: key.clone(),
+                defect,
+            });
+            corrupted.push((key, value));
+        }
+    }
+
+    // Entries are moved only after the walk, since not all backends allow
+    // modifying a table while iterating over it
+    if let Some(quarantine) = quarantine {
+        for (key, value) in corrupted {
+            let mut engine = sha256::Hash::engine();
+            engine.input(name.as_bytes());
+            engine.input(&[0u8]);
+            engine.input(&key);
+            let id = Slice32::from(sha256::Hash::from_engine(engine).into_inner());
+            let record = (name.to_owned(), (key.clone(), value)).strict_serialize()?;
+            quarantine.insert(id, &record)?;
+            table.remove_raw(&key)?;
+        }
+        quarantine.flush()?;
+        table.flush()?;
+    }
+    Ok(report)
+}
+
+fn check_entry(key: &[u8], value: &[u8], content_addressed: bool) -> Option<Defect> {
+    if key.len() != 32 {
+        return Some(Defect::KeyLength);
+    }
+    let chunk = Chunk::try_from(value).ok();
+    if content_addressed {
+        return match chunk {
+            None => Some(Defect::ValueType),
+            Some(chunk) if chunk.consensus_commit()[..] != *key => Some(Defect::IdMismatch),
+            Some(_) => None,
+        };
+    }
+    if chunk.is_none() && BTreeSet::<Slice32>::strict_deserialize(value).is_err() {
+        return Some(Defect::ValueType);
+    }
+    None
+}
+
+/// Checks integrity of all tables in the storage, except the quarantine one.
+/// Used by `stored fsck`, which runs while the daemon is stopped.
+pub fn run(
+    config: &Config,
+    quarantine: bool,
+) -> Result<BTreeMap<String, VerifyReport>, LaunchError> {
+    let db = backend::open(config)?;
+    let corrupt = match quarantine {
+        true => Some(db.open_table(QUARANTINE_TABLE)?),
+        false => None,
+    };
+    let mut reports = BTreeMap::new();
+    for name in db.table_names()? {
+        if name == QUARANTINE_TABLE {
+            continue;
+        }
+        let table = db.open_table(&name)?;
+        let content_addressed = config.content_addressed.contains(&name);
+        let report = verify_table(&name, table.as_ref(), content_addressed, corrupt.as_deref())?;
+        reports.insert(name, report);
+    }
+    db.flush()?;
+    Ok(reports)
+}
+
+#[cfg(test)]
+mod test {
+    use amplify::Slice32;
+    use commit_verify::ConsensusCommit;
+    use store_rpc::{BadEntry, Defect, PrimaryKey};
+    use storm::Chunk;
+
+    use super::{verify_table, QUARANTINE_TABLE};
+    use crate::backend::{MemoryBackend, StorageBackend};
+
+    #[test]
+    fn quarantine() {
+        let backend = MemoryBackend::new();
+        let table = backend.open_table("chunks").unwrap();
+        let corrupt = backend.open_table(QUARANTINE_TABLE).unwrap();
+        let chunk = Chunk::try_from(b"chunk".to_vec()).unwrap();
+        let key = chunk.consensus_commit().into_slice32();
+        table.insert(key, chunk.as_ref()).unwrap();
+        table.insert(Slice32::from([1u8; 32]), chunk.as_ref()).unwrap();
+
+        let report = verify_table("chunks", table.as_ref(), true, Some(corrupt.as_ref())).unwrap();
+        assert_eq!(report.checked, 2);
+        assert_eq!(report.bad, vec![BadEntry {
+            key: vec![1u8; 32],
+            defect: Defect::IdMismatch
+        }]);
+        assert_eq!(table.len().unwrap(), 1);
+        assert_eq!(corrupt.len().unwrap(), 1);
+
+        let report = verify_table("chunks", table.as_ref(), true, None).unwrap();
+        assert!(report.bad.is_empty());
+    }
+}
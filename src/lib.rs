@@ -14,13 +14,24 @@ extern crate amplify;
 #[macro_use]
 extern crate log;
 
+mod acl;
+pub mod backend;
+pub mod backup;
 mod config;
+mod durability;
 mod error;
+pub mod fsck;
+mod secure;
 pub mod service;
 #[cfg(feature = "server")]
 pub mod opts;
 
+pub use acl::{Acl, AclRule, Permission};
 pub use config::Config;
-pub use error::{DaemonError, LaunchError};
+pub use durability::Durability;
+pub use error::{BackendError, BackupError, DaemonError, LaunchError};
 
 pub(crate) const STORED_STORAGE_FILE: &str = "sled.db";
+pub(crate) const STORED_FS_DIR: &str = "tables";
+pub(crate) const STORED_NODE_KEY_FILE: &str = "node.key";
+pub(crate) const STORED_BACKUP_DIR: &str = "backups";
@@ -9,14 +9,17 @@
 // You should have received a copy of the MIT License along with this software.
 // If not, see <https://opensource.org/licenses/MIT>.
 
+use std::net::SocketAddr;
 use std::path::PathBuf;
 
-use clap::{Parser, ValueHint};
-use internet2::addr::ServiceAddr;
-use microservices::shell::shell_setup;
-use store_rpc::STORED_RPC_ENDPOINT;
+use clap::{Parser, Subcommand, ValueHint};
+use internet2::addr::{NodeId, ServiceAddr};
 
-#[cfg(any(target_os = "linux"))]
+use crate::acl::AclRule;
+use crate::backend::BackendType;
+use crate::durability::Durability;
+
+#[cfg(target_os = "linux")]
 pub const STORED_DATA_DIR: &str = "~/.storm_node";
 #[cfg(any(target_os = "freebsd", target_os = "openbsd", target_os = "netbsd"))]
 pub const STORED_DATA_DIR: &str = "~/.storm_node";
@@ -29,7 +32,12 @@ pub const STORED_DATA_DIR: &str = "~/Documents";
 #[cfg(target_os = "android")]
 pub const STORED_DATA_DIR: &str = ".";
 
+/// RPC endpoint used by default when encrypted sessions are enabled
+pub const STORED_LOCAL_RPC_ENDPOINT: &str = "127.0.0.1:60960";
 pub const STORED_CONFIG: &str = "{data_dir}/stored.toml";
+pub const STORED_THREADS: usize = 4;
+pub const STORED_FLUSH_INTERVAL: u64 = 100;
+pub const STORED_FLUSH_WRITES: u64 = 1000;
 
 /// Command-line arguments
 #[derive(Parser)]
@@ -42,41 +50,160 @@ pub struct Opts {
     #[clap(short, long, global = true, parse(from_occurrences))]
     pub verbose: u8,
 
+    /// Path to the configuration file.
+    ///
+    /// Defaults to `stored.toml` inside the data directory. Values from the
+    /// configuration file are overridden by environment variables and
+    /// command-line arguments.
+    #[clap(
+        short,
+        long,
+        global = true,
+        env = "STORED_CONFIG",
+        value_hint = ValueHint::FilePath
+    )]
+    pub config: Option<PathBuf>,
+
+    /// Rebuild the storage from a backup archive before starting.
+    ///
+    /// The archive is verified before the existing storage is replaced; the
+    /// replaced storage is kept in the data directory with `.old` suffix.
+    #[clap(long, value_hint = ValueHint::FilePath)]
+    pub restore: Option<PathBuf>,
+
+    /// Print effective configuration and exit.
+    #[clap(long, global = true)]
+    pub print_config: bool,
+
     /// Data directory path.
     ///
     /// Path to the directory that contains stored data, and where ZMQ RPC
-    /// socket files are located
+    /// socket files are located. Defaults to `~/.storm_node` on Linux and BSD
+    /// systems.
     #[clap(
         short,
         long,
         global = true,
-        default_value = STORED_DATA_DIR,
         env = "STORED_DATA_DIR",
         value_hint = ValueHint::DirPath
     )]
-    pub data_dir: PathBuf,
+    pub data_dir: Option<PathBuf>,
 
     /// ZMQ socket name/address for Storm Node client-server RPC API.
     ///
     /// Socket can be either TCP address in form of `<ipv4 | ipv6>:<port>` – or a path
-    /// to an IPC file.
+    /// to an IPC file. Defaults to `0.0.0.0:60960`, or to `127.0.0.1:60960` if
+    /// encrypted sessions are enabled with `--secure`.
     #[clap(
         short = 'X',
         long = "rpc",
         global = true,
         env = "STORED_RPC_ENDPOINT",
-        value_hint = ValueHint::FilePath,
-        default_value = STORED_RPC_ENDPOINT
+        value_hint = ValueHint::FilePath
     )]
-    pub rpc_endpoint: ServiceAddr,
+    pub rpc_endpoint: Option<ServiceAddr>,
+
+    /// ZMQ socket name/address for publishing change notifications.
+    ///
+    /// If given, the daemon publishes a notification each time a chunk is
+    /// stored, deleted or a set item is inserted. Socket can be either TCP
+    /// address in form of `<ipv4 | ipv6>:<port>` – or a path to an IPC file.
+    #[clap(
+        long = "pub",
+        global = true,
+        env = "STORED_PUB_ENDPOINT",
+        value_hint = ValueHint::FilePath
+    )]
+    pub pub_endpoint: Option<ServiceAddr>,
+
+    /// TCP address for accepting encrypted and authenticated client sessions.
+    ///
+    /// Sessions use Brontide protocol with the node key kept in `node.key`
+    /// file inside the data directory; the key is generated on the first
+    /// launch and its public part (node id) is reported in the log. Only
+    /// clients from the allow-list are accepted.
+    ///
+    /// Unencrypted RPC does not authenticate clients, so with this option it
+    /// must be bound to a loopback address or an IPC socket; otherwise the
+    /// daemon refuses to start.
+    #[clap(long, global = true, env = "STORED_SECURE_ENDPOINT")]
+    pub secure: Option<SocketAddr>,
+
+    /// Public key of a client allowed to use encrypted sessions.
+    ///
+    /// May be given multiple times.
+    #[clap(long = "allow", global = true, env = "STORED_ALLOW", value_delimiter = ',')]
+    pub allowed_clients: Vec<NodeId>,
+
+    /// Access rule in form of `<client>:<table>:<permissions>`.
+    ///
+    /// `client` is a client public key or `*` for any client, including ones
+    /// using unencrypted RPC; `table` is a table name or a prefix followed by
+    /// `*`; `permissions` is a comma-separated list of `read`, `write`,
+    /// `create` and `admin`. May be given multiple times. If no rules are
+    /// given, all clients have full access to all tables.
+    #[clap(long = "acl", global = true, env = "STORED_ACL", value_delimiter = ';')]
+    pub acl: Vec<AclRule>,
+
+    /// Storage backend to use for keeping the data.
+    ///
+    /// Can be `sled` for a persistent database inside the data directory, `fs`
+    /// for keeping each chunk in a separate file inside the data directory or
+    /// `memory` for a volatile in-memory storage. Defaults to `sled`.
+    #[clap(short, long, global = true, env = "STORED_BACKEND")]
+    pub backend: Option<BackendType>,
+
+    /// Number of worker threads processing client requests. Defaults to 4.
+    #[clap(short = 'T', long, global = true, env = "STORED_THREADS")]
+    pub threads: Option<usize>,
+
+    /// Durability mode for the written data.
+    ///
+    /// `sync` flushes each write to disk before replying to the client;
+    /// `group` flushes writes in groups, see `--flush-interval` and
+    /// `--flush-writes`; `os` leaves flushing to the storage backend and the
+    /// operating system. Defaults to `sync`.
+    #[clap(long, global = true, env = "STORED_DURABILITY")]
+    pub durability: Option<Durability>,
+
+    /// Interval in milliseconds between flushes in `group` durability mode.
+    /// Defaults to 100.
+    #[clap(long, global = true, env = "STORED_FLUSH_INTERVAL")]
+    pub flush_interval: Option<u64>,
+
+    /// Number of writes triggering a flush before the flush interval passes in
+    /// `group` durability mode. Defaults to 1000.
+    #[clap(long, global = true, env = "STORED_FLUSH_WRITES")]
+    pub flush_writes: Option<u64>,
+
+    /// Content-addressed table, keeping chunks under their ids.
+    ///
+    /// Chunks are stored into such tables without a key, and the daemon
+    /// verifies ids of the retrieved chunks. The table is opened on start. May
+    /// be given multiple times.
+    #[clap(long, global = true, env = "STORED_CONTENT_ADDRESSED", value_delimiter = ',')]
+    pub content_addressed: Vec<String>,
 
     /// Database table names to use.
     #[clap()]
     pub tables: Vec<String>,
+
+    /// Command to execute instead of running the daemon
+    #[clap(subcommand)]
+    pub command: Option<Command>,
 }
 
-impl Opts {
-    pub fn process(&mut self) {
-        shell_setup(self.verbose, [&mut self.rpc_endpoint], &mut self.data_dir, &[]);
-    }
+/// Commands executed instead of running the daemon
+#[derive(Subcommand)]
+#[derive(Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
+pub enum Command {
+    /// Check integrity of all data in the storage and exit.
+    ///
+    /// The daemon must not be running. Exits with code 1 if corrupted entries
+    /// were found and quarantined, and with code 4 if they were left in place.
+    Fsck {
+        /// Move corrupted entries into the `_corrupt` table.
+        #[clap(long)]
+        quarantine: bool,
+    },
 }
@@ -0,0 +1,148 @@
+// Storage daemon (stored): microservice frontend for different storage backends
+// used in LNP/BP nodes.
+//
+// Written in 2022 by
+//     Dr. Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// Copyright (C) 2022 by LNP/BP Standards Association, Switzerland.
+//
+// You should have received a copy of the MIT License along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Encrypted and authenticated client sessions over TCP, using Brontide
+//! protocol and the daemon node key.
+
+use std::collections::BTreeSet;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use internet2::addr::{InetSocketAddr, NodeId};
+use internet2::session::BrontideSession;
+use microservices::error::BootstrapError;
+use secp256k1::SecretKey;
+
+use crate::service::{Storage, Worker};
+use crate::LaunchError;
+
+/// Maximal number of concurrent client sessions, including the ones which have
+/// not completed the handshake yet
+const MAX_SESSIONS: usize = 64;
+
+/// Time given to a client to complete the handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Decrements number of the active sessions once the session ends.
+struct SessionGuard(Arc<AtomicUsize>);
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) { self.0.fetch_sub(1, Ordering::AcqRel); }
+}
+
+/// Starts thread accepting encrypted client sessions on the given address.
+/// Each session is served by a separate thread, up to [`MAX_SESSIONS`] at
+/// once; clients which public keys are not in the allow-list are disconnected
+/// right after the handshake.
+pub(crate) fn listen(
+    addr: SocketAddr,
+    node_key: &Path,
+    allowed_clients: &BTreeSet<NodeId>,
+    storage: Arc<Storage>,
+) -> Result<thread::JoinHandle<()>, BootstrapError<LaunchError>> {
+    let node = store_rpc::read_or_create_key(node_key)
+        .map_err(|err| BootstrapError::Io(format!("{}: {}", node_key.display(), err)))?;
+    info!("Accepting encrypted sessions on {} with node id {}", addr, node.node_id());
+    if allowed_clients.is_empty() {
+        warn!("Client allow-list is empty; all encrypted sessions will be rejected");
+    }
+
+    let listener = TcpListener::bind(addr).map_err(|err| BootstrapError::Io(err.to_string()))?;
+    let allowed_clients = allowed_clients.clone();
+    let local_key = node.private_key();
+    let sessions = Arc::new(AtomicUsize::new(0));
+    thread::Builder::new()
+        .name(s!("stored-secure"))
+        .spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) if sessions.load(Ordering::Acquire) >= MAX_SESSIONS => {
+                        warn!(
+                            "Rejecting connection from {:?}: too many client sessions",
+                            stream.peer_addr()
+                        );
+                    }
+                    Ok(stream) => {
+                        sessions.fetch_add(1, Ordering::AcqRel);
+                        let guard = SessionGuard(Arc::clone(&sessions));
+                        let allowed_clients = allowed_clients.clone();
+                        let storage = Arc::clone(&storage);
+                        let res =
+                            thread::Builder::new().name(s!("stored-session")).spawn(move || {
+                                serve(stream, local_key, &allowed_clients, storage);
+                                drop(guard);
+                            });
+                        if let Err(err) = res {
+                            error!("Unable to start client session thread: {}", err);
+                        }
+                    }
+                    Err(err) => warn!("Unable to accept client connection: {}", err),
+                }
+            }
+        })
+        .map_err(|err| BootstrapError::Io(err.to_string()))
+}
+
+fn serve(
+    stream: TcpStream,
+    local_key: SecretKey,
+    allowed_clients: &BTreeSet<NodeId>,
+    storage: Arc<Storage>,
+) {
+    let peer = match stream.peer_addr() {
+        Ok(peer) => peer,
+        Err(err) => {
+            warn!("Unable to get address of the connected client: {}", err);
+            return;
+        }
+    };
+    // Unauthenticated peers must not hold the session thread forever, so the
+    // handshake is limited in time; the timeout is shared with the session,
+    // which owns a clone of the stream
+    let timeouts =
+        match stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT)).and_then(|_| stream.try_clone()) {
+            Ok(timeouts) => timeouts,
+            Err(err) => {
+                warn!("Unable to configure connection with client {}: {}", peer, err);
+                return;
+            }
+        };
+    let session = match BrontideSession::with(stream, local_key, InetSocketAddr::from(peer)) {
+        Ok(session) => session,
+        Err(err) => {
+            warn!("Handshake with client {} has failed: {}", peer, err);
+            return;
+        }
+    };
+    let client = session.remote_id();
+    if !allowed_clients.contains(&client) {
+        warn!("Rejecting client {}@{} which is not in the allow-list", client, peer);
+        return;
+    }
+    if let Err(err) = timeouts.set_read_timeout(None) {
+        warn!("Unable to configure session with client {}@{}: {}", client, peer, err);
+        return;
+    }
+    debug!("Encrypted session with client {}@{} established", client, peer);
+
+    let mut worker =
+        Worker::with(format!("{}@{}", client, peer), Box::new(session), Some(client), storage);
+    loop {
+        if let Err(err) = worker.run() {
+            debug!("Session with client {}@{} has ended: {}", client, peer, err);
+            break;
+        }
+    }
+}
@@ -9,43 +9,90 @@
 // You should have received a copy of the MIT License along with this software.
 // If not, see <https://opensource.org/licenses/MIT>.
 
-use std::collections::{BTreeSet, HashMap};
+use std::cell::RefCell;
+use std::collections::hash_map::Entry;
+use std::collections::{btree_map, BTreeMap, BTreeSet, HashMap};
+use std::ops::Bound;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::{fs, thread};
 
 use amplify::Slice32;
 use bitcoin_hashes::Hash;
 use commit_verify::commit_encode::ConsensusCommit;
+use internet2::addr::{NodeId, ServiceAddr};
 use internet2::session::LocalSession;
 use internet2::{
-    CreateUnmarshaller, SendRecvMessage, TypedEnum, Unmarshall, Unmarshaller, ZmqSocketType,
+    transport, CreateUnmarshaller, SendRecvMessage, TypedEnum, Unmarshall, Unmarshaller,
+    ZmqSocketType,
 };
 use microservices::error::BootstrapError;
 use microservices::node::TryService;
-use microservices::rpc::ClientError;
+use microservices::rpc::{self, ClientError};
 use microservices::ZMQ_CONTEXT;
-use store_rpc::{CheckUnknownReq, InsertReq, PrimaryKey, Reply, Request, RetrieveReq, StoreReq};
+use nix::sys::signal::{SigSet, Signal};
+use store_rpc::{
+    BackupReq, CasReq, CheckUnknownReq, Event, IdsPage, IdsPartition, InsertReq, ListIdsReq,
+    Operation, PrimaryKey, RenameTableReq, Reply, Request, RetrieveBatchReq, RetrieveReq,
+    StoreBatchReq, StoreContentReq, StoreReq, TransactionReq, TxOp, TxResult, VerifyReq,
+    LIST_IDS_PAGE_LIMIT,
+};
 use storm::{Chunk, ChunkId};
 use strict_encoding::{StrictDecode, StrictEncode};
 
-use crate::{Config, DaemonError, LaunchError, STORED_STORAGE_FILE};
+use crate::backend::{self, StorageBackend, Table, TxView};
+use crate::{
+    backup, fsck, secure, Acl, BackendError, BackupError, Config, DaemonError, Durability,
+    LaunchError, Permission, STORED_BACKUP_DIR, STORED_NODE_KEY_FILE,
+};
+
+type Tables = HashMap<String, Arc<dyn Table>>;
+
+/// In-process endpoint used by worker threads to receive requests
+const WORKERS_ENDPOINT: &str = "inproc://stored-workers";
+
+/// In-process endpoint receiving commands which control the request proxy
+const CONTROL_ENDPOINT: &str = "inproc://stored-control";
+
+/// Time in milliseconds to wait for the replies of the completed requests
+/// during shutdown
+const SHUTDOWN_DRAIN_TIMEOUT: i64 = 100;
 
 pub fn run(config: Config) -> Result<(), BootstrapError<LaunchError>> {
     let runtime = Runtime::init(config)?;
 
-    runtime.run_or_panic("stored");
+    runtime.try_run_loop()?;
 
     Ok(())
 }
 
+/// Asks the runtime to stop accepting requests and to shut down the daemon.
+fn trigger_shutdown() -> Result<(), zmq::Error> {
+    let socket = ZMQ_CONTEXT.socket(zmq::PUSH)?;
+    socket.connect(CONTROL_ENDPOINT)?;
+    socket.send("TERMINATE", 0)
+}
+
+/// Runtime accepting client requests and distributing them across the pool of
+/// worker threads. Replies are routed back to the clients which have sent the
+/// requests.
 pub struct Runtime {
-    /// Stored sessions
-    pub(super) session_rpc: LocalSession,
+    /// ROUTER socket accepting client connections
+    pub(super) frontend: zmq::Socket,
 
-    /// Unmarshaller instance used for parsing RPC request
-    pub(super) unmarshaller: Unmarshaller<Request>,
+    /// DEALER socket distributing requests to the workers
+    pub(super) backend: zmq::Socket,
+
+    /// PULL socket receiving proxy control commands
+    pub(super) control: zmq::Socket,
 
-    pub(super) db: sled::Db,
+    pub(super) workers: Vec<thread::JoinHandle<()>>,
 
-    pub(super) trees: HashMap<String, sled::Tree>,
+    pub(super) storage: Arc<Storage>,
+
+    /// IPC socket files to be removed on shutdown
+    pub(super) ipc_files: Vec<PathBuf>,
 }
 
 impl Runtime {
@@ -53,124 +100,841 @@ impl Runtime {
         // debug!("Initializing storage provider {:?}", config.storage_conf());
         // let storage = storage::FileDriver::with(config.storage_conf())?;
 
+        if let (Some(_), ServiceAddr::Tcp(addr)) = (config.secure_endpoint, &config.rpc_endpoint) {
+            if !addr.ip().is_loopback() {
+                return Err(LaunchError::ExposedRpc(config.rpc_endpoint.clone()).into());
+            }
+        }
+
+        // Signals are blocked before any thread is spawned, so all threads
+        // inherit the mask and the signals are received only by the thread
+        // waiting for them
+        let mut signals = SigSet::empty();
+        signals.add(Signal::SIGTERM);
+        signals.add(Signal::SIGINT);
+        signals.thread_block().map_err(|err| BootstrapError::Io(err.to_string()))?;
+
+        let publisher = match config.pub_endpoint {
+            None => None,
+            Some(ref endpoint) => {
+                debug!("Opening notification socket {}", endpoint);
+                let socket = ZMQ_CONTEXT.socket(zmq::PUB)?;
+                socket.bind(&endpoint.zmq_connect_string())?;
+                Some(socket)
+            }
+        };
+        let storage = Arc::new(Storage::init(&config, publisher)?);
+
         debug!("Opening RPC API socket {}", config.rpc_endpoint);
-        let session_rpc = LocalSession::connect(
-            ZmqSocketType::Rep,
-            &config.rpc_endpoint,
-            None,
-            None,
-            &ZMQ_CONTEXT,
-        )?;
+        let frontend = ZMQ_CONTEXT.socket(zmq::ROUTER)?;
+        frontend.bind(&config.rpc_endpoint.zmq_connect_string())?;
+        let backend = ZMQ_CONTEXT.socket(zmq::DEALER)?;
+        backend.bind(WORKERS_ENDPOINT)?;
+        let control = ZMQ_CONTEXT.socket(zmq::PULL)?;
+        control.bind(CONTROL_ENDPOINT)?;
+
+        debug!("Starting {} worker thread(s)", config.threads);
+        let mut workers = (0..config.threads.max(1))
+            .map(|no| Worker::spawn(no, Arc::clone(&storage)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if let Some(addr) = config.secure_endpoint {
+            let node_key = config.data_dir.join(STORED_NODE_KEY_FILE);
+            let storage = Arc::clone(&storage);
+            workers.push(secure::listen(addr, &node_key, &config.allowed_clients, storage)?);
+        }
+
+        if config.durability == Durability::Group {
+            let storage = Arc::clone(&storage);
+            let interval = config.flush_interval;
+            let flusher = thread::Builder::new()
+                .name(s!("stored-flusher"))
+                .spawn(move || loop {
+                    thread::sleep(interval);
+                    if let Err(err) = storage.flush_pending() {
+                        error!("Unable to flush storage: {}", err);
+                    }
+                })
+                .map_err(|err| BootstrapError::Io(err.to_string()))?;
+            workers.push(flusher);
+        }
 
-        let (db, trees) = Self::init_db(&config)?;
+        thread::Builder::new()
+            .name(s!("stored-signals"))
+            .spawn(move || match signals.wait() {
+                Ok(signal) => {
+                    info!("Received {}, shutting down", signal);
+                    if let Err(err) = trigger_shutdown() {
+                        error!("Unable to initiate shutdown: {}", err);
+                    }
+                }
+                Err(err) => error!("Unable to receive termination signals: {}", err),
+            })
+            .map_err(|err| BootstrapError::Io(err.to_string()))?;
 
         info!("Stored runtime started successfully");
 
         Ok(Self {
-            session_rpc,
-            unmarshaller: Request::create_unmarshaller(),
-            db,
-            trees,
+            frontend,
+            backend,
+            control,
+            workers,
+            storage,
+            ipc_files: config.ipc_files(),
         })
     }
 
-    fn init_db(config: &Config) -> Result<(sled::Db, HashMap<String, sled::Tree>), LaunchError> {
-        let mut db_path = config.data_dir.clone();
-        db_path.push(STORED_STORAGE_FILE);
-        debug!("Opening database at {}", db_path.display());
-        let db = sled::open(db_path)?;
-        let trees = config
-            .databases
-            .iter()
-            .map(|name| db.open_tree(name).map(|tree| (name.clone(), tree)))
-            .collect::<Result<HashMap<_, _>, _>>()?;
-        Ok((db, trees))
+    /// Completes requests in flight, delivers their replies to the clients,
+    /// flushes all data to disk and removes IPC socket files. Must be called
+    /// once the proxy has stopped, so no new requests are accepted.
+    fn shutdown(self) -> Result<(), LaunchError> {
+        self.storage.shutdown()?;
+        // Replies to the completed requests may still be queued
+        while self.backend.poll(zmq::POLLIN, SHUTDOWN_DRAIN_TIMEOUT)? > 0 {
+            let reply = self.backend.recv_multipart(0)?;
+            self.frontend.send_multipart(reply, 0)?;
+        }
+        for path in &self.ipc_files {
+            debug!("Removing socket file {}", path.display());
+            if let Err(err) = fs::remove_file(path) {
+                warn!("Unable to remove socket file {}: {}", path.display(), err);
+            }
+        }
+        info!("Stored has stopped");
+        Ok(())
     }
 }
 
 impl TryService for Runtime {
+    type ErrorType = LaunchError;
+
+    fn try_run_loop(mut self) -> Result<(), Self::ErrorType> {
+        trace!("Proxying requests to {} worker(s)", self.workers.len());
+        loop {
+            // Proxy returns normally only once it gets terminated through the
+            // control socket; an interrupted system call must not bring the
+            // daemon down
+            match zmq::proxy_steerable(&mut self.frontend, &mut self.backend, &mut self.control) {
+                Ok(()) => break,
+                Err(zmq::Error::EINTR) => debug!("Request proxy was interrupted, resuming"),
+                Err(err) => {
+                    error!("Request proxy has failed: {}", err);
+                    return Err(err.into());
+                }
+            }
+        }
+        self.shutdown()
+    }
+}
+
+/// Worker thread processing requests received from the runtime or from an
+/// encrypted client session.
+pub struct Worker {
+    /// Worker name, used for logging
+    pub(super) name: String,
+
+    /// Authenticated client served by the worker; `None` for unencrypted RPC
+    pub(super) client: Option<NodeId>,
+
+    /// Stored sessions
+    pub(super) session_rpc: Box<dyn SendRecvMessage>,
+
+    /// Unmarshaller instance used for parsing RPC request
+    pub(super) unmarshaller: Unmarshaller<Request>,
+
+    pub(super) storage: Arc<Storage>,
+
+    /// Number of recoverable errors happened during the worker lifetime
+    pub(super) errors: u64,
+}
+
+impl Worker {
+    pub(crate) fn with(
+        name: String,
+        session_rpc: Box<dyn SendRecvMessage>,
+        client: Option<NodeId>,
+        storage: Arc<Storage>,
+    ) -> Self {
+        Worker {
+            name,
+            client,
+            session_rpc,
+            unmarshaller: Request::create_unmarshaller(),
+            storage,
+            errors: 0,
+        }
+    }
+
+    fn spawn(
+        no: usize,
+        storage: Arc<Storage>,
+    ) -> Result<thread::JoinHandle<()>, BootstrapError<LaunchError>> {
+        let socket = ZMQ_CONTEXT.socket(zmq::REP)?;
+        socket.connect(WORKERS_ENDPOINT)?;
+        let handle = thread::Builder::new()
+            .name(format!("stored-worker-{}", no))
+            .spawn(move || {
+                let session = LocalSession::with_zmq_socket(ZmqSocketType::Rep, socket);
+                let worker = Worker::with(format!("#{}", no), Box::new(session), None, storage);
+                // The pool can't serve requests with a worker missing, so the
+                // daemon stops instead of running degraded
+                if worker.try_run_loop().is_err() {
+                    if let Err(err) = trigger_shutdown() {
+                        error!("Unable to initiate shutdown: {}", err);
+                    }
+                }
+            })
+            .map_err(|err| BootstrapError::Io(err.to_string()))?;
+        Ok(handle)
+    }
+}
+
+impl TryService for Worker {
     type ErrorType = ClientError;
 
     fn try_run_loop(mut self) -> Result<(), Self::ErrorType> {
         loop {
             match self.run() {
                 Ok(_) => debug!("API request processing complete"),
+                Err(err) if is_fatal(&err) => {
+                    error!("Worker {} is unable to continue: {}", self.name, err);
+                    return Err(err);
+                }
                 Err(err) => {
-                    error!("Error processing API request: {}", err);
-                    Err(err)?;
+                    self.errors += 1;
+                    warn!(
+                        "Worker {} has failed to process API request (error #{}): {}",
+                        self.name, self.errors, err
+                    );
                 }
             }
         }
     }
 }
 
-impl Runtime {
-    fn run(&mut self) -> Result<(), ClientError> {
-        trace!("Awaiting for ZMQ RPC requests...");
-        let raw = self.session_rpc.recv_raw_message()?;
+impl Worker {
+    pub(crate) fn run(&mut self) -> Result<(), ClientError> {
+        trace!("Worker {} awaiting for RPC requests...", self.name);
+        let raw = match self.session_rpc.recv_raw_message() {
+            Ok(raw) => raw,
+            Err(err @ transport::Error::Zmq(_)) | Err(err @ transport::Error::SocketIo(_)) => {
+                return Err(err.into())
+            }
+            Err(err) => {
+                // The message was read from the socket, but its frame is broken;
+                // the client still awaits the reply
+                let reply = Reply::Failure(rpc::Failure {
+                    code: rpc::FailureCode::Framing,
+                    info: err.to_string(),
+                });
+                if let Err(err) = self.session_rpc.send_raw_message(&reply.serialize()) {
+                    debug!("Unable to report framing error to the client: {}", err);
+                }
+                return Err(err.into());
+            }
+        };
         let reply = self.rpc_process(raw).unwrap_or_else(|err| err);
-        trace!("Preparing ZMQ RPC reply: {:?}", reply);
+        trace!("Preparing RPC reply: {:?}", reply);
         let data = reply.serialize();
-        trace!("Sending {} bytes back to the client over ZMQ RPC", data.len());
+        trace!("Sending {} bytes back to the client over RPC", data.len());
         self.session_rpc.send_raw_message(&data)?;
         Ok(())
     }
-}
 
-impl Runtime {
     pub(crate) fn rpc_process(&mut self, raw: Vec<u8>) -> Result<Reply, Reply> {
-        trace!("Got {} bytes over ZMQ RPC", raw.len());
-        let request = (*self.unmarshaller.unmarshall(raw.as_slice())?).clone();
-        debug!("Received ZMQ RPC request #{}: {}", request.get_type(), request);
+        trace!("Got {} bytes over RPC", raw.len());
+        let request = match self.unmarshaller.unmarshall(raw.as_slice()) {
+            Ok(request) => (*request).clone(),
+            Err(err) => {
+                self.errors += 1;
+                warn!(
+                    "Worker {} got malformed request (error #{}): {}",
+                    self.name, self.errors, err
+                );
+                return Err(err.into());
+            }
+        };
+        debug!("Received RPC request #{}: {}", request.get_type(), request);
+        self.storage.authorize(self.client, &request)?;
+        self.storage.process(request).map_err(Reply::from)
+    }
+}
+
+/// Detects errors after which the worker socket can't be used anymore, like
+/// socket teardown on daemon shutdown. All other errors, like a broken peer or
+/// a failed send, affect a single request only.
+fn is_fatal(err: &ClientError) -> bool {
+    match err {
+        ClientError::Transport(transport::Error::Zmq(err)) => matches!(
+            zmq::Error::from(*err),
+            zmq::Error::ETERM | zmq::Error::ENOTSOCK | zmq::Error::EFSM
+        ),
+        _ => false,
+    }
+}
+
+/// Storage state shared by all worker threads.
+pub struct Storage {
+    pub(super) db: Box<dyn StorageBackend>,
+
+    pub(super) trees: RwLock<Tables>,
+
+    /// PUB socket for change notifications, shared by all workers
+    pub(super) publisher: Option<Mutex<zmq::Socket>>,
+
+    /// Per-table access rules
+    pub(super) acl: Acl,
+
+    /// Set once the daemon shuts down. Requests are processed under the read
+    /// lock, so the shutdown waits for the requests in flight to complete.
+    pub(super) stopped: RwLock<bool>,
+
+    /// Durability mode for write requests
+    pub(super) durability: Durability,
+
+    /// Number of writes triggering a flush in `group` durability mode
+    pub(super) flush_writes: u64,
+
+    /// Number of writes which were not flushed yet
+    pub(super) pending: AtomicU64,
+
+    /// Tables keeping chunks under their ids
+    pub(super) content_addressed: BTreeSet<String>,
+
+    /// Data location, against which relative backup paths are resolved
+    pub(super) data_dir: PathBuf,
+}
+
+impl Storage {
+    fn init(config: &Config, publisher: Option<zmq::Socket>) -> Result<Self, LaunchError> {
+        debug!("Opening {} storage backend", config.backend);
+        let db = backend::open(config)?;
+        // Tables created during previous runs are opened together with the ones
+        // requested by the configuration
+        let mut names = db.table_names()?;
+        debug!("Found {} existing table(s) in the storage", names.len());
+        names.extend(config.databases.iter().cloned());
+        names.extend(config.content_addressed.iter().cloned());
+        let trees = names
+            .into_iter()
+            .map(|name| db.open_table(&name).map(|tree| (name, Arc::from(tree))))
+            .collect::<Result<HashMap<_, _>, _>>()?;
+        Ok(Storage {
+            db,
+            trees: RwLock::new(trees),
+            publisher: publisher.map(Mutex::new),
+            acl: config.acl.clone(),
+            stopped: RwLock::new(false),
+            durability: config.durability,
+            flush_writes: config.flush_writes.max(1),
+            pending: AtomicU64::new(0),
+            content_addressed: config.content_addressed.clone(),
+            data_dir: config.data_dir.clone(),
+        })
+    }
+
+    /// Checks that the client has permissions required by the request.
+    fn authorize(&self, client: Option<NodeId>, request: &Request) -> Result<(), DaemonError> {
+        // Daemon-wide operations require permission for all tables
+        let all_tables = s!("*");
+        let (tables, permission) = match request {
+            Request::Transaction(TransactionReq { ops }) => {
+                for op in ops {
+                    self.check_permission(client, op.table(), Permission::Write)?;
+                }
+                return Ok(());
+            }
+            Request::Shutdown | Request::Backup(_) => (vec![&all_tables], Permission::Admin),
+            Request::Tables => (vec![&all_tables], Permission::Read),
+            Request::Flush => (vec![&all_tables], Permission::Write),
+            // Opening an existing table requires only read access
+            Request::Use(table) if self.tables().contains_key(table) => {
+                (vec![table], Permission::Read)
+            }
+            Request::Use(table) => (vec![table], Permission::Create),
+            Request::DropTable(table) | Request::ClearTable(table) => {
+                (vec![table], Permission::Admin)
+            }
+            Request::RenameTable(RenameTableReq { from, to }) => {
+                (vec![from, to], Permission::Admin)
+            }
+            Request::Count(table)
+            | Request::ListIds(table)
+            | Request::Verify(VerifyReq {
+                table,
+                quarantine: false,
+            }) => (vec![table], Permission::Read),
+            Request::Verify(VerifyReq {
+                table,
+                quarantine: true,
+            }) => (vec![table], Permission::Admin),
+            Request::Retrieve(RetrieveReq { table, .. })
+            | Request::Members(RetrieveReq { table, .. })
+            | Request::MemberCount(RetrieveReq { table, .. })
+            | Request::IsMember(InsertReq { table, .. })
+            | Request::ListIdsPage(ListIdsReq { table, .. })
+            | Request::CheckUnknown(CheckUnknownReq { table, .. })
+            | Request::PartitionIds(CheckUnknownReq { table, .. })
+            | Request::RetrieveBatch(RetrieveBatchReq { table, .. }) => {
+                (vec![table], Permission::Read)
+            }
+            Request::Store(StoreReq { table, .. })
+            | Request::StoreContent(StoreContentReq { table, .. })
+            | Request::StoreIfAbsent(StoreReq { table, .. })
+            | Request::CompareAndSwap(CasReq { table, .. })
+            | Request::StoreBatch(StoreBatchReq { table, .. })
+            | Request::Insert(InsertReq { table, .. })
+            | Request::RemoveMember(InsertReq { table, .. })
+            | Request::Delete(RetrieveReq { table, .. }) => (vec![table], Permission::Write),
+        };
+        for table in tables {
+            self.check_permission(client, table, permission)?;
+        }
+        Ok(())
+    }
+
+    fn check_permission(
+        &self,
+        client: Option<NodeId>,
+        table: &str,
+        permission: Permission,
+    ) -> Result<(), DaemonError> {
+        if !self.acl.is_permitted(client, table, permission) {
+            return Err(DaemonError::PermissionDenied {
+                table: table.to_owned(),
+                permission,
+            });
+        }
+        Ok(())
+    }
+
+    /// Publishes notification about a change in the storage, if notifications
+    /// are enabled. Failure to publish does not affect the request, since the
+    /// change has already happened.
+    fn notify(&self, table: &str, key: Slice32, chunk_id: ChunkId, operation: Operation) {
+        let publisher = match self.publisher {
+            None => return,
+            Some(ref publisher) => publisher,
+        };
+        let event = Event {
+            table: table.to_owned(),
+            key,
+            chunk_id,
+            operation,
+        };
+        trace!("Publishing notification {}", event);
+        let res = event.to_frames().map_err(|err| err.to_string()).and_then(|frames| {
+            let socket = publisher.lock().expect("notification socket lock is poisoned");
+            socket.send_multipart(frames, 0).map_err(|err| err.to_string())
+        });
+        if let Err(err) = res {
+            warn!("Unable to publish notification {}: {}", event, err);
+        }
+    }
+
+    /// Stops processing of new requests, waiting for the ones in flight, and
+    /// flushes all tables to disk.
+    fn shutdown(&self) -> Result<(), BackendError> {
+        let mut stopped = self.stopped.write().expect("shutdown lock is poisoned");
+        *stopped = true;
+        self.flush_all()
+    }
+
+    /// Makes the write durable according to the configured durability mode.
+    fn commit(&self, tree: &dyn Table) -> Result<(), BackendError> {
+        match self.durability {
+            Durability::Sync => tree.flush(),
+            Durability::Group => {
+                if self.pending.fetch_add(1, Ordering::AcqRel) + 1 >= self.flush_writes {
+                    self.flush_pending()?;
+                }
+                Ok(())
+            }
+            Durability::Os => Ok(()),
+        }
+    }
+
+    /// Flushes all tables if some of the writes were not flushed yet.
+    fn flush_pending(&self) -> Result<(), BackendError> {
+        let pending = self.pending.swap(0, Ordering::AcqRel);
+        if pending == 0 {
+            return Ok(());
+        }
+        trace!("Flushing {} pending write(s)", pending);
+        self.flush_all().map_err(|err| {
+            self.pending.fetch_add(pending, Ordering::AcqRel);
+            err
+        })
+    }
+
+    /// Flushes all tables and the database to disk.
+    fn flush_all(&self) -> Result<(), BackendError> {
+        for (name, tree) in self.tables().iter() {
+            debug!("Flushing table {}", name);
+            tree.flush()?;
+        }
+        self.db.flush()
+    }
+
+    fn tables(&self) -> RwLockReadGuard<'_, Tables> {
+        self.trees.read().expect("table registry lock is poisoned")
+    }
+
+    fn tables_mut(&self) -> RwLockWriteGuard<'_, Tables> {
+        self.trees.write().expect("table registry lock is poisoned")
+    }
+
+    fn table(&self, table: &str) -> Result<Arc<dyn Table>, DaemonError> {
+        self.tables().get(table).cloned().ok_or_else(|| DaemonError::UnknownTable(table.to_owned()))
+    }
+
+    pub(crate) fn process(&self, request: Request) -> Result<Reply, DaemonError> {
+        // Changes to the set of tables pause processing of all other requests,
+        // so that no request writes into a table which is being renamed or
+        // dropped
+        let request = match request {
+            Request::DropTable(table) => return self.exclusive(|| self.drop_table(table)),
+            Request::ClearTable(table) => return self.exclusive(|| self.clear_table(table)),
+            Request::RenameTable(RenameTableReq { from, to }) => {
+                return self.exclusive(|| self.rename_table(from, to))
+            }
+            Request::Verify(VerifyReq {
+                table,
+                quarantine: true,
+            }) => return self.exclusive(|| self.verify(table, true)),
+            request => request,
+        };
+        let stopped = self.stopped.read().expect("shutdown lock is poisoned");
+        if *stopped {
+            return Err(DaemonError::ShuttingDown);
+        }
         match request {
+            Request::Shutdown => {
+                trigger_shutdown().map_err(DaemonError::Shutdown)?;
+                Ok(Reply::Success)
+            }
+            Request::Flush => self.flush(),
+            Request::Backup(BackupReq { path }) => self.backup(path),
+            Request::DropTable(_)
+            | Request::ClearTable(_)
+            | Request::RenameTable(_)
+            | Request::Verify(VerifyReq {
+                quarantine: true, ..
+            }) => {
+                unreachable!("exclusive requests are processed without the shutdown lock")
+            }
+            Request::Transaction(TransactionReq { ops }) => self.transaction(ops),
             Request::Use(table) => self.use_table(table),
             Request::Tables => self.list_tables(),
             Request::Count(table) => self.count(table),
+            Request::Verify(VerifyReq { table, .. }) => self.verify(table, false),
             Request::Store(StoreReq { table, key, chunk }) => self.store(table, key, chunk),
+            Request::StoreContent(StoreContentReq { table, chunk }) => {
+                self.store_content(table, chunk)
+            }
+            Request::StoreIfAbsent(StoreReq { table, key, chunk }) => {
+                self.check_content_key(&table, key, chunk.consensus_commit())?;
+                self.compare_and_swap(table, key, None, chunk)
+            }
+            Request::CompareAndSwap(CasReq {
+                table,
+                key,
+                expected,
+                chunk,
+            }) => {
+                self.check_not_content_addressed(&table)?;
+                self.compare_and_swap(table, key, expected, chunk)
+            }
             Request::Retrieve(RetrieveReq { table, key }) => self.retrieve(table, key),
             Request::Insert(InsertReq { table, key, item }) => self.insert(table, key, item),
             Request::ListIds(table) => self.list_ids(table),
-            Request::CheckUnknown(CheckUnknownReq { table, ids }) => self.filter_ids(table, ids),
+            Request::ListIdsPage(ListIdsReq {
+                table,
+                after,
+                until,
+                limit,
+            }) => self.list_ids_page(table, after, until, limit),
+            Request::CheckUnknown(CheckUnknownReq { table, ids }) => {
+                let partition = self.partition_ids(table, ids)?;
+                Ok(Reply::Ids(partition.unknown))
+            }
+            Request::PartitionIds(CheckUnknownReq { table, ids }) => {
+                self.partition_ids(table, ids).map(Reply::IdsPartition)
+            }
+            Request::Delete(RetrieveReq { table, key }) => self.delete(table, key),
+            Request::Members(RetrieveReq { table, key }) => self.members(table, key),
+            Request::RemoveMember(InsertReq { table, key, item }) => {
+                self.remove_member(table, key, item)
+            }
+            Request::IsMember(InsertReq { table, key, item }) => self.is_member(table, key, item),
+            Request::MemberCount(RetrieveReq { table, key }) => self.member_count(table, key),
+            Request::StoreBatch(StoreBatchReq { table, chunks }) => self.store_batch(table, chunks),
+            Request::RetrieveBatch(RetrieveBatchReq { table, keys }) => {
+                self.retrieve_batch(table, keys)
+            }
+        }
+    }
+
+    fn use_table(&self, table: String) -> Result<Reply, DaemonError> {
+        if let Entry::Vacant(entry) = self.tables_mut().entry(table) {
+            let tree = self.db.open_table(entry.key())?;
+            entry.insert(Arc::from(tree));
+        }
+        Ok(Reply::Success)
+    }
+
+    fn flush(&self) -> Result<Reply, DaemonError> {
+        self.pending.store(0, Ordering::Release);
+        self.flush_all()?;
+        Ok(Reply::Success)
+    }
+
+    /// Runs the operation holding the shutdown lock exclusively, so that no
+    /// other requests are processed until it completes.
+    fn exclusive(
+        &self,
+        op: impl FnOnce() -> Result<Reply, DaemonError>,
+    ) -> Result<Reply, DaemonError> {
+        let stopped = self.stopped.write().expect("shutdown lock is poisoned");
+        if *stopped {
+            return Err(DaemonError::ShuttingDown);
         }
-        .map_err(Reply::from)
+        op()
     }
 
-    fn use_table(&mut self, table: String) -> Result<Reply, DaemonError> {
-        let tree = self.db.open_tree(&table)?;
-        self.trees.insert(table, tree);
+    /// Writes snapshot of all tables into the archive. Each table is archived
+    /// as it was when its snapshot was taken, while other requests are
+    /// processed concurrently.
+    fn backup(&self, path: String) -> Result<Reply, DaemonError> {
+        // Clients may write archives only inside the backup directory
+        let relative = Path::new(&path);
+        if relative.as_os_str().is_empty()
+            || !relative.components().all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(BackupError::InvalidPath(path).into());
+        }
+        let path = self.data_dir.join(STORED_BACKUP_DIR).join(relative);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(BackupError::from)?;
+        }
+        let mut tables = self
+            .tables()
+            .iter()
+            .map(|(name, tree)| (name.clone(), tree.clone()))
+            .collect::<BTreeMap<_, _>>();
+        for name in self.db.table_names()? {
+            if let btree_map::Entry::Vacant(entry) = tables.entry(name) {
+                let tree = self.db.open_table(entry.key())?;
+                entry.insert(Arc::from(tree));
+            }
+        }
+        let entries =
+            backup::write(&path, tables.iter().map(|(name, tree)| (name.as_str(), tree.as_ref())))?;
+        info!("Backup of {} entries is written to {}", entries, path.display());
         Ok(Reply::Success)
     }
 
     fn list_tables(&self) -> Result<Reply, DaemonError> {
-        let tables = self.trees.keys().cloned().collect();
+        let mut tables = self.db.table_names()?;
+        tables.extend(self.tables().keys().cloned());
         Ok(Reply::Tables(tables))
     }
 
+    fn drop_table(&self, table: String) -> Result<Reply, DaemonError> {
+        // Table mode is configured by name, so content-addressed tables must
+        // keep their names
+        self.check_not_content_addressed(&table)?;
+        let mut trees = self.tables_mut();
+        let opened = trees.remove(&table).is_some();
+        if !self.db.drop_table(&table)? && !opened {
+            return Err(DaemonError::UnknownTable(table));
+        }
+        self.db.flush()?;
+        Ok(Reply::Success)
+    }
+
+    fn clear_table(&self, table: String) -> Result<Reply, DaemonError> {
+        let tree = self.table(&table)?;
+        tree.clear()?;
+        tree.flush()?;
+        Ok(Reply::Success)
+    }
+
+    fn rename_table(&self, from: String, to: String) -> Result<Reply, DaemonError> {
+        self.check_not_content_addressed(&from)?;
+        self.check_not_content_addressed(&to)?;
+        let mut trees = self.tables_mut();
+        if !trees.contains_key(&from) {
+            return Err(DaemonError::UnknownTable(from));
+        }
+        if trees.contains_key(&to) || self.db.table_names()?.contains(&to) {
+            return Err(DaemonError::TableExists(to));
+        }
+        let tree = self.db.rename_table(&from, &to)?;
+        trees.remove(&from);
+        trees.insert(to, Arc::from(tree));
+        self.db.flush()?;
+        Ok(Reply::Success)
+    }
+
     fn count(&self, table: String) -> Result<Reply, DaemonError> {
-        let tree = self.trees.get(&table).ok_or(DaemonError::UnknownTable(table))?;
-        let count = tree.len();
+        let tree = self.table(&table)?;
+        let count = tree.len()?;
         Ok(Reply::Count(count as u64))
     }
 
+    /// Checks integrity of the table entries. Quarantining the entries must be
+    /// run through [`Storage::exclusive`], so no requests modify them while
+    /// they are moved.
+    fn verify(&self, table: String, quarantine: bool) -> Result<Reply, DaemonError> {
+        let tree = self.table(&table)?;
+        let content_addressed = self.is_content_addressed(&table);
+        // Entries of the quarantine table itself have nowhere to be moved
+        let corrupt = match quarantine && table != fsck::QUARANTINE_TABLE {
+            true => {
+                self.use_table(fsck::QUARANTINE_TABLE.to_owned())?;
+                Some(self.table(fsck::QUARANTINE_TABLE)?)
+            }
+            false => None,
+        };
+        let report =
+            fsck::verify_table(&table, tree.as_ref(), content_addressed, corrupt.as_deref())?;
+        Ok(Reply::Verified(report))
+    }
+
     fn store(
         &self,
         table: String,
         key: impl PrimaryKey,
         chunk: Chunk,
     ) -> Result<Reply, DaemonError> {
-        let tree = self.trees.get(&table).ok_or(DaemonError::UnknownTable(table))?;
         let chunk_id = chunk.consensus_commit();
-        tree.insert(key.into_slice32(), chunk.as_ref())?;
-        tree.flush()?;
+        let key = key.into_slice32();
+        if self.is_content_addressed(&table) {
+            self.check_content_key(&table, key, chunk_id)?;
+            return self.store_content(table, chunk);
+        }
+        let tree = self.table(&table)?;
+        tree.insert(key, chunk.as_ref())?;
+        self.commit(tree.as_ref())?;
+        self.notify(&table, key, chunk_id, Operation::Store);
+        Ok(Reply::ChunkId(chunk_id))
+    }
+
+    fn store_content(&self, table: String, chunk: Chunk) -> Result<Reply, DaemonError> {
+        if !self.is_content_addressed(&table) {
+            return Err(DaemonError::NotContentAddressed(table));
+        }
+        let tree = self.table(&table)?;
+        let chunk_id = chunk.consensus_commit();
+        let key = chunk_id.into_slice32();
+        // Chunk already stored under the id has the same data, so repeated
+        // stores leave the table intact
+        if tree.compare_and_swap(key, None, Some(chunk.as_ref()))?.is_ok() {
+            self.commit(tree.as_ref())?;
+            self.notify(&table, key, chunk_id, Operation::Store);
+        }
+        Ok(Reply::ChunkId(chunk_id))
+    }
+
+    fn compare_and_swap(
+        &self,
+        table: String,
+        key: Slice32,
+        expected: Option<ChunkId>,
+        chunk: Chunk,
+    ) -> Result<Reply, DaemonError> {
+        let tree = self.table(&table)?;
+        let current = tree.get(key)?;
+        let current_id = match current {
+            None => None,
+            Some(ref data) => Some(Self::read_chunk(key, data.clone())?.consensus_commit()),
+        };
+        if current_id != expected {
+            return Ok(Reply::Mismatch(current_id));
+        }
+        // The value may be changed by a concurrent request after we have read
+        // it, so the backend re-checks it atomically
+        if let Err(actual) = tree.compare_and_swap(key, current.as_deref(), Some(chunk.as_ref()))? {
+            let actual_id = match actual {
+                None => None,
+                Some(data) => Some(Self::read_chunk(key, data)?.consensus_commit()),
+            };
+            return Ok(Reply::Mismatch(actual_id));
+        }
+        self.commit(tree.as_ref())?;
+        let chunk_id = chunk.consensus_commit();
+        self.notify(&table, key, chunk_id, Operation::Store);
         Ok(Reply::ChunkId(chunk_id))
     }
 
     fn retrieve(&self, table: String, key: impl PrimaryKey) -> Result<Reply, DaemonError> {
         let key = key.into_slice32();
-        let tree = self.trees.get(&table).ok_or(DaemonError::UnknownTable(table))?;
+        let tree = self.table(&table)?;
         Ok(match tree.get(key)? {
             None => Reply::KeyAbsent(key),
-            Some(data) => Reply::Chunk(data.as_ref().try_into()?),
+            Some(data) => Reply::Chunk(self.verify_chunk(&table, key, data)?),
+        })
+    }
+
+    fn store_batch(
+        &self,
+        table: String,
+        chunks: BTreeMap<Slice32, Chunk>,
+    ) -> Result<Reply, DaemonError> {
+        let tree = self.table(&table)?;
+        let content_addressed = self.is_content_addressed(&table);
+        let mut batch = Vec::with_capacity(chunks.len());
+        let mut chunk_ids = BTreeMap::new();
+        for (key, chunk) in chunks {
+            let chunk_id = chunk.consensus_commit();
+            chunk_ids.insert(key, chunk_id);
+            if content_addressed {
+                self.check_content_key(&table, key, chunk_id)?;
+                if tree.contains_key(key)? {
+                    continue;
+                }
+            }
+            batch.push((key, chunk.to_vec()));
+        }
+        let stored = batch.iter().map(|(key, _)| *key).collect::<Vec<_>>();
+        tree.apply_batch(batch)?;
+        self.commit(tree.as_ref())?;
+        for key in stored {
+            self.notify(&table, key, chunk_ids[&key], Operation::Store);
+        }
+        Ok(Reply::ChunkIds(chunk_ids))
+    }
+
+    fn retrieve_batch(&self, table: String, keys: BTreeSet<Slice32>) -> Result<Reply, DaemonError> {
+        let tree = self.table(&table)?;
+        let chunks = keys
+            .into_iter()
+            .map(|key| -> Result<_, DaemonError> {
+                let chunk = match tree.get(key)? {
+                    None => None,
+                    Some(data) => Some(self.verify_chunk(&table, key, data)?),
+                };
+                Ok((key, chunk))
+            })
+            .collect::<Result<BTreeMap<_, _>, _>>()?;
+        Ok(Reply::Chunks(chunks))
+    }
+
+    fn delete(&self, table: String, key: impl PrimaryKey) -> Result<Reply, DaemonError> {
+        let key = key.into_slice32();
+        let tree = self.table(&table)?;
+        let removed = match tree.remove(key)? {
+            None => None,
+            Some(data) => Some(Self::read_chunk(key, data)?.consensus_commit()),
+        };
+        self.commit(tree.as_ref())?;
+        Ok(match removed {
+            None => Reply::KeyAbsent(key),
+            Some(chunk_id) => {
+                self.notify(&table, key, chunk_id, Operation::Delete);
+                Reply::ChunkId(chunk_id)
+            }
         })
     }
 
@@ -180,45 +944,1305 @@ impl Runtime {
         key: impl PrimaryKey,
         item: Slice32,
     ) -> Result<Reply, DaemonError> {
+        self.check_not_content_addressed(&table)?;
         let key = key.into_slice32();
-        let tree = self.trees.get(&table).ok_or(DaemonError::UnknownTable(table))?;
-        let data = tree.get(key)?.unwrap_or_default();
-        let mut set = if data.is_empty() {
-            BTreeSet::new()
-        } else {
-            BTreeSet::<Slice32>::strict_deserialize(data)?
-        };
-        set.insert(item);
-        tree.insert(key, set.strict_serialize()?)?;
-        tree.flush()?;
+        let tree = self.table(&table)?;
+        if Self::update_set(tree.as_ref(), key, |set| set.insert(item))? {
+            self.commit(tree.as_ref())?;
+        }
+        self.notify(&table, key, ChunkId::from_inner(item.into_array()), Operation::Insert);
         Ok(Reply::Success)
     }
 
-    fn list_ids(&self, table: String) -> Result<Reply, DaemonError> {
-        let tree = self.trees.get(&table).ok_or(DaemonError::UnknownTable(table))?;
-        let keys = tree
-            .range::<&[u8], _>(..)
-            .map(|res| match res {
-                Ok((ivec, _)) => Ok(ChunkId::from_slice(&ivec)
-                    .map_err(|_| sled::Error::ReportableBug(s!("non-standard id")))?),
-                Err(e) => Err(e),
-            })
-            .collect::<Result<BTreeSet<_>, sled::Error>>()?;
-        Ok(Reply::Ids(keys))
+    fn members(&self, table: String, key: impl PrimaryKey) -> Result<Reply, DaemonError> {
+        let tree = self.table(&table)?;
+        let set = Self::read_set(tree.as_ref(), key.into_slice32())?;
+        Ok(Reply::Members(set))
+    }
+
+    fn remove_member(
+        &self,
+        table: String,
+        key: impl PrimaryKey,
+        item: Slice32,
+    ) -> Result<Reply, DaemonError> {
+        self.check_not_content_addressed(&table)?;
+        let key = key.into_slice32();
+        let tree = self.table(&table)?;
+        if !Self::update_set(tree.as_ref(), key, |set| set.remove(&item))? {
+            return Ok(Reply::Membership(false));
+        }
+        self.commit(tree.as_ref())?;
+        Ok(Reply::Membership(true))
+    }
+
+    fn is_member(
+        &self,
+        table: String,
+        key: impl PrimaryKey,
+        item: Slice32,
+    ) -> Result<Reply, DaemonError> {
+        let tree = self.table(&table)?;
+        let set = Self::read_set(tree.as_ref(), key.into_slice32())?;
+        Ok(Reply::Membership(set.contains(&item)))
+    }
+
+    fn member_count(&self, table: String, key: impl PrimaryKey) -> Result<Reply, DaemonError> {
+        let tree = self.table(&table)?;
+        let set = Self::read_set(tree.as_ref(), key.into_slice32())?;
+        Ok(Reply::Count(set.len() as u64))
     }
 
-    fn filter_ids(&self, table: String, mut ids: BTreeSet<ChunkId>) -> Result<Reply, DaemonError> {
-        let tree = self.trees.get(&table).ok_or(DaemonError::UnknownTable(table))?;
-        // TODO: Improve efficiency by restricting the range
-        for res in tree.range::<&[u8], _>(..) {
-            let (ivec, _) = res?;
-            let id = ChunkId::from_slice(&ivec).map_err(|_| {
-                DaemonError::Encoding(strict_encoding::Error::DataIntegrityError(s!(
-                    "invalid chunk id data"
-                )))
+    fn transaction(&self, ops: Vec<TxOp>) -> Result<Reply, DaemonError> {
+        // Inside the transaction tables are referenced by their index
+        let mut names = Vec::<&str>::new();
+        let mut trees = Vec::new();
+        let mut indexes = Vec::with_capacity(ops.len());
+        for (no, op) in ops.iter().enumerate() {
+            self.check_tx_op(op).map_err(|err| DaemonError::TxAborted {
+                op: no,
+                err: Box::new(err),
             })?;
-            ids.remove(&id);
+            let name = op.table();
+            let no = match names.iter().position(|known| *known == name) {
+                Some(no) => no,
+                None => {
+                    trees.push(self.table(name)?);
+                    names.push(name);
+                    names.len() - 1
+                }
+            };
+            indexes.push(no);
         }
-        Ok(Reply::Ids(ids))
+
+        let results = RefCell::new(Vec::with_capacity(ops.len()));
+        self.db.transaction(&names, &|view| {
+            let mut results = results.borrow_mut();
+            // The transaction may be retried by the backend
+            results.clear();
+            for (no, (op, table)) in ops.iter().zip(&indexes).enumerate() {
+                let result = Self::tx_op(view, *table, op).map_err(|err| match err {
+                    err @ DaemonError::Database(BackendError::Conflict) => err,
+                    err => DaemonError::TxAborted {
+                        op: no,
+                        err: Box::new(err),
+                    },
+                })?;
+                results.push(result);
+            }
+            Ok(())
+        })?;
+        for tree in &trees {
+            self.commit(tree.as_ref())?;
+        }
+
+        let results = results.into_inner();
+        for (op, result) in ops.iter().zip(&results) {
+            match (op, result) {
+                (TxOp::Store(StoreReq { table, key, .. }), TxResult::Stored(chunk_id)) => {
+                    self.notify(table, *key, *chunk_id, Operation::Store)
+                }
+                (TxOp::Insert(InsertReq { table, key, item }), _) => {
+                    let chunk_id = ChunkId::from_inner(item.into_array());
+                    self.notify(table, *key, chunk_id, Operation::Insert)
+                }
+                (TxOp::Delete(RetrieveReq { table, key }), TxResult::Deleted(Some(chunk_id))) => {
+                    self.notify(table, *key, *chunk_id, Operation::Delete)
+                }
+                _ => {}
+            }
+        }
+        Ok(Reply::TxResults(results))
+    }
+
+    fn tx_op(view: &dyn TxView, table: usize, op: &TxOp) -> Result<TxResult, DaemonError> {
+        Ok(match op {
+            TxOp::Store(StoreReq { key, chunk, .. }) => {
+                view.insert(table, *key, chunk.as_ref())?;
+                TxResult::Stored(chunk.consensus_commit())
+            }
+            TxOp::Insert(InsertReq { key, item, .. }) => {
+                let mut set = Self::decode_set(*key, view.get(table, *key)?)?;
+                set.insert(*item);
+                view.insert(table, *key, &set.strict_serialize()?)?;
+                TxResult::Inserted
+            }
+            TxOp::Delete(RetrieveReq { key, .. }) => {
+                let removed = match view.remove(table, *key)? {
+                    None => None,
+                    Some(data) => Some(Self::read_chunk(*key, data)?.consensus_commit()),
+                };
+                TxResult::Deleted(removed)
+            }
+            TxOp::RemoveMember(InsertReq { key, item, .. }) => {
+                let mut set = Self::decode_set(*key, view.get(table, *key)?)?;
+                let removed = set.remove(item);
+                if removed && set.is_empty() {
+                    view.remove(table, *key)?;
+                } else if removed {
+                    view.insert(table, *key, &set.strict_serialize()?)?;
+                }
+                TxResult::Removed(removed)
+            }
+        })
+    }
+
+    /// Checks that the operation is compatible with the mode of its table.
+    fn check_tx_op(&self, op: &TxOp) -> Result<(), DaemonError> {
+        match op {
+            TxOp::Store(StoreReq { table, key, chunk }) => {
+                self.check_content_key(table, *key, chunk.consensus_commit())
+            }
+            TxOp::Insert(InsertReq { table, .. }) | TxOp::RemoveMember(InsertReq { table, .. }) => {
+                self.check_not_content_addressed(table)
+            }
+            TxOp::Delete(_) => Ok(()),
+        }
+    }
+
+    fn is_content_addressed(&self, table: &str) -> bool { self.content_addressed.contains(table) }
+
+    /// Checks that a chunk stored into a content-addressed table is keyed by
+    /// its id; keys in other tables are not restricted.
+    fn check_content_key(
+        &self,
+        table: &str,
+        key: Slice32,
+        chunk_id: ChunkId,
+    ) -> Result<(), DaemonError> {
+        if self.is_content_addressed(table) && key != chunk_id.into_slice32() {
+            return Err(DaemonError::KeyMismatch { key, chunk_id });
+        }
+        Ok(())
+    }
+
+    /// Fails for content-addressed tables, which keep only chunks under their
+    /// ids, preventing operations which may break this invariant.
+    fn check_not_content_addressed(&self, table: &str) -> Result<(), DaemonError> {
+        if self.is_content_addressed(table) {
+            return Err(DaemonError::ContentAddressed(table.to_owned()));
+        }
+        Ok(())
+    }
+
+    /// Reads chunk retrieved from the table, checking that chunks from
+    /// content-addressed tables hash to the key they are stored under.
+    fn verify_chunk(&self, table: &str, key: Slice32, data: Vec<u8>) -> Result<Chunk, DaemonError> {
+        let chunk = Self::read_chunk(key, data)?;
+        if self.is_content_addressed(table) {
+            let chunk_id = chunk.consensus_commit();
+            if key != chunk_id.into_slice32() {
+                return Err(DaemonError::Corrupted { key, chunk_id });
+            }
+        }
+        Ok(chunk)
+    }
+
+    /// Reads set stored under the key; absent key is treated as an empty set.
+    fn read_set(tree: &dyn Table, key: Slice32) -> Result<BTreeSet<Slice32>, DaemonError> {
+        Self::decode_set(key, tree.get(key)?)
+    }
+
+    /// Applies the change to the set stored under the key, returning whether
+    /// the set was changed. Concurrent requests may change the set after it
+    /// was read, so the set is written with compare-and-swap and the change is
+    /// re-applied to the current value until the swap succeeds. Empty sets
+    /// are removed from the table.
+    fn update_set(
+        tree: &dyn Table,
+        key: Slice32,
+        change: impl Fn(&mut BTreeSet<Slice32>) -> bool,
+    ) -> Result<bool, DaemonError> {
+        let mut current = tree.get(key)?;
+        loop {
+            let mut set = Self::decode_set(key, current.clone())?;
+            if !change(&mut set) {
+                return Ok(false);
+            }
+            let new = if set.is_empty() { None } else { Some(set.strict_serialize()?) };
+            match tree.compare_and_swap(key, current.as_deref(), new.as_deref())? {
+                Ok(()) => return Ok(true),
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    fn decode_set(key: Slice32, data: Option<Vec<u8>>) -> Result<BTreeSet<Slice32>, DaemonError> {
+        let data = data.unwrap_or_default();
+        Ok(if data.is_empty() {
+            BTreeSet::new()
+        } else {
+            BTreeSet::<Slice32>::strict_deserialize(data).map_err(|_| {
+                DaemonError::ValueTypeMismatch {
+                    key,
+                    expected: "set",
+                }
+            })?
+        })
+    }
+
+    fn read_chunk(key: Slice32, data: Vec<u8>) -> Result<Chunk, DaemonError> {
+        Chunk::try_from(data).map_err(|_| DaemonError::ValueTypeMismatch {
+            key,
+            expected: "chunk",
+        })
+    }
+
+    fn list_ids(&self, table: String) -> Result<Reply, DaemonError> {
+        let tree = self.table(&table)?;
+        let keys = tree
+            .range(Bound::Unbounded, Bound::Unbounded)
+            .map(|res| Self::chunk_id(&res?.0))
+            .collect::<Result<BTreeSet<_>, _>>()?;
+        Ok(Reply::Ids(keys))
+    }
+
+    fn list_ids_page(
+        &self,
+        table: String,
+        after: Option<ChunkId>,
+        until: Option<ChunkId>,
+        limit: u32,
+    ) -> Result<Reply, DaemonError> {
+        let tree = self.table(&table)?;
+        // Zero limit would return an empty page with no cursor, which looks like
+        // the end of the listing, so we use the maximal page size instead
+        let limit = match limit {
+            0 => LIST_IDS_PAGE_LIMIT,
+            limit => limit.min(LIST_IDS_PAGE_LIMIT),
+        } as usize;
+        let start = after.map(|id| Bound::Excluded(id.into_slice32())).unwrap_or(Bound::Unbounded);
+        let end = until.map(|id| Bound::Excluded(id.into_slice32())).unwrap_or(Bound::Unbounded);
+        // We read one extra id to learn whether there is a next page
+        let mut ids = tree
+            .range(start, end)
+            .take(limit + 1)
+            .map(|res| Self::chunk_id(&res?.0))
+            .collect::<Result<Vec<_>, _>>()?;
+        let next = if ids.len() > limit {
+            ids.truncate(limit);
+            ids.last().copied()
+        } else {
+            None
+        };
+        let ids = ids.into_iter().collect();
+        Ok(Reply::IdsPage(IdsPage { ids, next }))
+    }
+
+    fn partition_ids(
+        &self,
+        table: String,
+        ids: BTreeSet<ChunkId>,
+    ) -> Result<IdsPartition, DaemonError> {
+        let tree = self.table(&table)?;
+        let mut partition = IdsPartition::default();
+        for id in ids {
+            if tree.contains_key(id.into_slice32())? {
+                partition.known.insert(id);
+            } else {
+                partition.unknown.insert(id);
+            }
+        }
+        Ok(partition)
+    }
+
+    fn chunk_id(key: &[u8]) -> Result<ChunkId, DaemonError> {
+        ChunkId::from_slice(key).map_err(|_| {
+            DaemonError::Encoding(strict_encoding::Error::DataIntegrityError(s!(
+                "non-standard chunk id"
+            )))
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::any::Any;
+    use std::collections::HashSet;
+    use std::mem;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use internet2::RoutedFrame;
+    use store_rpc::FailureCode;
+
+    use super::*;
+    use crate::backend::{BackendType, MemoryBackend, TxFn};
+    use crate::STORED_FS_DIR;
+
+    /// Configuration of a daemon keeping the `chunks` table in memory
+    fn config() -> Config {
+        Config {
+            rpc_endpoint: ServiceAddr::Inproc(s!("stored-test")),
+            pub_endpoint: None,
+            secure_endpoint: None,
+            allowed_clients: bset![],
+            acl: Acl::default(),
+            data_dir: std::env::temp_dir(),
+            backend: BackendType::Memory,
+            threads: 1,
+            durability: Durability::Sync,
+            flush_interval: Duration::from_millis(100),
+            flush_writes: 1000,
+            databases: HashSet::from([s!("chunks")]),
+            content_addressed: bset![],
+            verbose: 0,
+        }
+    }
+
+    fn key(byte: u8) -> Slice32 { Slice32::from([byte; 32]) }
+
+    fn chunk(data: &[u8]) -> Chunk { Chunk::try_from(data.to_vec()).unwrap() }
+
+    #[test]
+    fn delete() {
+        let storage = Storage::init(&config(), None).unwrap();
+        let req = RetrieveReq {
+            table: s!("chunks"),
+            key: key(1),
+        };
+        let chunk_id = chunk(b"one").consensus_commit();
+        let store = Request::Store(StoreReq {
+            table: s!("chunks"),
+            key: key(1),
+            chunk: chunk(b"one"),
+        });
+        assert_eq!(storage.process(store), Ok(Reply::ChunkId(chunk_id)));
+        assert_eq!(storage.process(Request::Delete(req.clone())), Ok(Reply::ChunkId(chunk_id)));
+        assert_eq!(storage.process(Request::Delete(req.clone())), Ok(Reply::KeyAbsent(key(1))));
+        assert_eq!(storage.process(Request::Retrieve(req)), Ok(Reply::KeyAbsent(key(1))));
+        let req = RetrieveReq {
+            table: s!("none"),
+            key: key(1),
+        };
+        assert_eq!(
+            storage.process(Request::Delete(req)),
+            Err(DaemonError::UnknownTable(s!("none")))
+        );
+    }
+
+    #[test]
+    fn batch() {
+        let storage = Storage::init(&config(), None).unwrap();
+        let chunks = bmap! { key(1) => chunk(b"one"), key(2) => chunk(b"two") };
+        let chunk_ids =
+            chunks.iter().map(|(key, chunk)| (*key, chunk.consensus_commit())).collect();
+        assert_eq!(
+            storage.process(Request::StoreBatch(StoreBatchReq {
+                table: s!("chunks"),
+                chunks: chunks.clone(),
+            })),
+            Ok(Reply::ChunkIds(chunk_ids))
+        );
+        assert_eq!(
+            storage.process(Request::RetrieveBatch(RetrieveBatchReq {
+                table: s!("chunks"),
+                keys: bset![key(1), key(2), key(3)],
+            })),
+            Ok(Reply::Chunks(bmap! {
+                key(1) => Some(chunks[&key(1)].clone()),
+                key(2) => Some(chunks[&key(2)].clone()),
+                key(3) => None
+            }))
+        );
+    }
+
+    #[test]
+    fn list_ids_page() {
+        let storage = Storage::init(&config(), None).unwrap();
+        let mut ids = (1..=3u8)
+            .map(|no| {
+                let chunk = chunk(&[no]);
+                let chunk_id = chunk.consensus_commit();
+                let request = Request::Store(StoreReq {
+                    table: s!("chunks"),
+                    key: chunk_id.into_slice32(),
+                    chunk,
+                });
+                storage.process(request).unwrap();
+                chunk_id
+            })
+            .collect::<Vec<_>>();
+        ids.sort();
+        let page = |after: Option<ChunkId>, limit: u32| {
+            storage.process(Request::ListIdsPage(ListIdsReq {
+                table: s!("chunks"),
+                after,
+                until: None,
+                limit,
+            }))
+        };
+
+        assert_eq!(
+            page(None, 2),
+            Ok(Reply::IdsPage(IdsPage {
+                ids: bset![ids[0], ids[1]],
+                next: Some(ids[1]),
+            }))
+        );
+        assert_eq!(
+            page(Some(ids[1]), 2),
+            Ok(Reply::IdsPage(IdsPage {
+                ids: bset![ids[2]],
+                next: None,
+            }))
+        );
+        // Zero limit must not end the listing prematurely
+        assert_eq!(
+            page(None, 0),
+            Ok(Reply::IdsPage(IdsPage {
+                ids: ids.iter().copied().collect(),
+                next: None,
+            }))
+        );
+    }
+
+    #[test]
+    fn check_unknown() {
+        let storage = Storage::init(&config(), None).unwrap();
+        let known = chunk(b"known").consensus_commit();
+        let unknown = chunk(b"unknown").consensus_commit();
+        let request = Request::Store(StoreReq {
+            table: s!("chunks"),
+            key: known.into_slice32(),
+            chunk: chunk(b"known"),
+        });
+        storage.process(request).unwrap();
+        let req = CheckUnknownReq {
+            table: s!("chunks"),
+            ids: bset![known, unknown],
+        };
+
+        assert_eq!(
+            storage.process(Request::CheckUnknown(req.clone())),
+            Ok(Reply::Ids(bset![unknown]))
+        );
+        assert_eq!(
+            storage.process(Request::PartitionIds(req)),
+            Ok(Reply::IdsPartition(IdsPartition {
+                known: bset![known],
+                unknown: bset![unknown],
+            }))
+        );
+    }
+
+    #[test]
+    fn set_members() {
+        let storage = Storage::init(&config(), None).unwrap();
+        let member = |item| InsertReq {
+            table: s!("chunks"),
+            key: key(9),
+            item,
+        };
+        let set = RetrieveReq {
+            table: s!("chunks"),
+            key: key(9),
+        };
+        for item in [key(1), key(2)] {
+            assert_eq!(storage.process(Request::Insert(member(item))), Ok(Reply::Success));
+        }
+        assert_eq!(
+            storage.process(Request::Members(set.clone())),
+            Ok(Reply::Members(bset![key(1), key(2)]))
+        );
+        assert_eq!(storage.process(Request::IsMember(member(key(2)))), Ok(Reply::Membership(true)));
+        assert_eq!(storage.process(Request::MemberCount(set)), Ok(Reply::Count(2)));
+        for item in [key(1), key(2)] {
+            let request = Request::RemoveMember(member(item));
+            assert_eq!(storage.process(request), Ok(Reply::Membership(true)));
+        }
+        assert_eq!(
+            storage.process(Request::RemoveMember(member(key(1)))),
+            Ok(Reply::Membership(false))
+        );
+        assert_eq!(
+            storage.process(Request::IsMember(member(key(2)))),
+            Ok(Reply::Membership(false))
+        );
+        // Empty set is removed from the table
+        assert_eq!(storage.process(Request::Count(s!("chunks"))), Ok(Reply::Count(0)));
+    }
+
+    #[test]
+    fn concurrent_set_updates() {
+        let storage = Arc::new(Storage::init(&config(), None).unwrap());
+        let member = |item| InsertReq {
+            table: s!("chunks"),
+            key: key(9),
+            item,
+        };
+        let inserters = (0..8u8)
+            .map(|no| {
+                let storage = Arc::clone(&storage);
+                thread::spawn(move || {
+                    for item in (no * 25)..(no * 25 + 25) {
+                        storage.process(Request::Insert(member(key(item)))).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        inserters.into_iter().for_each(|inserter| inserter.join().unwrap());
+        let set = RetrieveReq {
+            table: s!("chunks"),
+            key: key(9),
+        };
+        assert_eq!(storage.process(Request::MemberCount(set)), Ok(Reply::Count(200)));
+
+        let removers = (0..8u8)
+            .map(|no| {
+                let storage = Arc::clone(&storage);
+                thread::spawn(move || {
+                    for item in (no * 25)..(no * 25 + 25) {
+                        let reply = storage.process(Request::RemoveMember(member(key(item))));
+                        assert_eq!(reply, Ok(Reply::Membership(true)));
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        removers.into_iter().for_each(|remover| remover.join().unwrap());
+        assert_eq!(storage.process(Request::Count(s!("chunks"))), Ok(Reply::Count(0)));
+    }
+
+    #[test]
+    fn value_type_mismatch() {
+        let storage = Storage::init(&config(), None).unwrap();
+        let request = Request::Store(StoreReq {
+            table: s!("chunks"),
+            key: key(1),
+            chunk: chunk(b"chunk"),
+        });
+        storage.process(request).unwrap();
+        let req = RetrieveReq {
+            table: s!("chunks"),
+            key: key(1),
+        };
+        let err = storage.process(Request::Members(req)).unwrap_err();
+        assert_eq!(err, DaemonError::ValueTypeMismatch {
+            key: key(1),
+            expected: "set"
+        });
+        assert_eq!(err.failure_code(), FailureCode::ValueTypeMismatch);
+
+        // Chunks with the layout of a set are still retrieved as chunks
+        let mut data = vec![0x01, 0x00];
+        data.extend([0xAA; 32]);
+        let request = Request::Store(StoreReq {
+            table: s!("chunks"),
+            key: key(2),
+            chunk: chunk(&data),
+        });
+        storage.process(request).unwrap();
+        let req = RetrieveReq {
+            table: s!("chunks"),
+            key: key(2),
+        };
+        assert_eq!(storage.process(Request::Retrieve(req)), Ok(Reply::Chunk(chunk(&data))));
+        let req = RetrieveBatchReq {
+            table: s!("chunks"),
+            keys: bset![key(2)],
+        };
+        assert_eq!(
+            storage.process(Request::RetrieveBatch(req)),
+            Ok(Reply::Chunks(bmap! { key(2) => Some(chunk(&data)) }))
+        );
+    }
+
+    #[test]
+    fn table_discovery() {
+        let dir = std::env::temp_dir().join(format!("stored-discovery-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut config = Config {
+            backend: BackendType::Fs,
+            data_dir: dir.clone(),
+            ..config()
+        };
+        let storage = Storage::init(&config, None).unwrap();
+        assert_eq!(storage.process(Request::Use(s!("extra"))), Ok(Reply::Success));
+        let request = Request::Store(StoreReq {
+            table: s!("extra"),
+            key: key(1),
+            chunk: chunk(b"one"),
+        });
+        storage.process(request).unwrap();
+        drop(storage);
+
+        config.databases.clear();
+        let storage = Storage::init(&config, None).unwrap();
+        assert_eq!(
+            storage.process(Request::Tables),
+            Ok(Reply::Tables(bset![s!("chunks"), s!("extra")]))
+        );
+        assert_eq!(storage.process(Request::Count(s!("extra"))), Ok(Reply::Count(1)));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn table_lifecycle() {
+        let storage = Storage::init(&config(), None).unwrap();
+        let request = Request::Store(StoreReq {
+            table: s!("chunks"),
+            key: key(1),
+            chunk: chunk(b"one"),
+        });
+        storage.process(request).unwrap();
+        assert_eq!(storage.process(Request::Use(s!("other"))), Ok(Reply::Success));
+
+        let rename = |from: &str, to: &str| {
+            Request::RenameTable(RenameTableReq {
+                from: from.to_owned(),
+                to: to.to_owned(),
+            })
+        };
+        let err = storage.process(rename("chunks", "other")).unwrap_err();
+        assert_eq!(err, DaemonError::TableExists(s!("other")));
+        assert_eq!(err.failure_code(), FailureCode::TableExists);
+        assert_eq!(
+            storage.process(rename("none", "renamed")),
+            Err(DaemonError::UnknownTable(s!("none")))
+        );
+        assert_eq!(storage.process(rename("chunks", "renamed")), Ok(Reply::Success));
+        assert_eq!(
+            storage.process(Request::Tables),
+            Ok(Reply::Tables(bset![s!("other"), s!("renamed")]))
+        );
+        let req = RetrieveReq {
+            table: s!("renamed"),
+            key: key(1),
+        };
+        assert_eq!(storage.process(Request::Retrieve(req)), Ok(Reply::Chunk(chunk(b"one"))));
+
+        assert_eq!(storage.process(Request::ClearTable(s!("renamed"))), Ok(Reply::Success));
+        assert_eq!(storage.process(Request::Count(s!("renamed"))), Ok(Reply::Count(0)));
+
+        assert_eq!(storage.process(Request::DropTable(s!("renamed"))), Ok(Reply::Success));
+        assert_eq!(
+            storage.process(Request::DropTable(s!("renamed"))),
+            Err(DaemonError::UnknownTable(s!("renamed")))
+        );
+        assert_eq!(storage.process(Request::Tables), Ok(Reply::Tables(bset![s!("other")])));
+    }
+
+    #[test]
+    fn concurrent_rename() {
+        let numbered_key = |no: u32| {
+            let mut key = [0u8; 32];
+            key[..4].copy_from_slice(&no.to_be_bytes());
+            Slice32::from(key)
+        };
+        let rename = |storage: &Storage, from: &str, to: &str| {
+            storage.process(Request::RenameTable(RenameTableReq {
+                from: from.to_owned(),
+                to: to.to_owned(),
+            }))
+        };
+        let storage = Arc::new(Storage::init(&config(), None).unwrap());
+
+        // Rename waits for the requests in flight, which hold the shutdown lock
+        let in_flight = storage.stopped.read().unwrap();
+        let renaming = {
+            let storage = Arc::clone(&storage);
+            thread::spawn(move || rename(&storage, "chunks", "renamed"))
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(storage.tables().contains_key("chunks"));
+        drop(in_flight);
+        assert_eq!(renaming.join().unwrap(), Ok(Reply::Success));
+
+        let writers = (0..4u32)
+            .map(|no| {
+                let storage = Arc::clone(&storage);
+                thread::spawn(move || {
+                    let mut stored = 0u64;
+                    for no in (no << 24).. {
+                        let request = Request::Store(StoreReq {
+                            table: s!("renamed"),
+                            key: numbered_key(no),
+                            chunk: chunk(b"data"),
+                        });
+                        match storage.process(request) {
+                            Ok(_) => stored += 1,
+                            Err(DaemonError::UnknownTable(_)) => break,
+                            Err(err) => panic!("unexpected error {}", err),
+                        }
+                    }
+                    stored
+                })
+            })
+            .collect::<Vec<_>>();
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(rename(&storage, "renamed", "chunks"), Ok(Reply::Success));
+
+        // All writes confirmed to the clients before the rename are kept
+        let stored = writers.into_iter().map(|writer| writer.join().unwrap()).sum::<u64>();
+        assert_eq!(storage.process(Request::Count(s!("chunks"))), Ok(Reply::Count(stored)));
+    }
+
+    #[test]
+    fn daemon_wide_permissions() {
+        let config = Config {
+            acl: Acl::with(["*:chunks:read,write".parse().unwrap()]),
+            ..config()
+        };
+        let storage = Storage::init(&config, None).unwrap();
+        let denied = |permission| {
+            Err(DaemonError::PermissionDenied {
+                table: s!("*"),
+                permission,
+            })
+        };
+        let store = Request::Store(StoreReq {
+            table: s!("chunks"),
+            key: key(1),
+            chunk: chunk(b"one"),
+        });
+        assert_eq!(storage.authorize(None, &Request::Tables), denied(Permission::Read));
+        assert_eq!(storage.authorize(None, &Request::Flush), denied(Permission::Write));
+        assert_eq!(storage.authorize(None, &store), Ok(()));
+
+        let config = Config {
+            acl: Acl::with(["*:*:read,write".parse().unwrap()]),
+            ..config
+        };
+        let storage = Storage::init(&config, None).unwrap();
+        assert_eq!(storage.authorize(None, &Request::Tables), Ok(()));
+        assert_eq!(storage.authorize(None, &Request::Flush), Ok(()));
+        assert_eq!(storage.authorize(None, &Request::Shutdown), denied(Permission::Admin));
+    }
+
+    #[test]
+    fn notifications() {
+        let endpoint = "inproc://stored-test-notifications";
+        let publisher = ZMQ_CONTEXT.socket(zmq::PUB).unwrap();
+        publisher.bind(endpoint).unwrap();
+        let subscriber = ZMQ_CONTEXT.socket(zmq::SUB).unwrap();
+        subscriber.connect(endpoint).unwrap();
+        subscriber.set_subscribe(b"").unwrap();
+        let storage = Storage::init(&config(), Some(publisher)).unwrap();
+        let delete = || {
+            storage.process(Request::Delete(RetrieveReq {
+                table: s!("chunks"),
+                key: key(1),
+            }))
+        };
+
+        // Subscription reaches the publisher asynchronously, so we repeat the
+        // request until the first notification is received
+        let chunk_id = chunk(b"one").consensus_commit();
+        let frames = loop {
+            let request = Request::Store(StoreReq {
+                table: s!("chunks"),
+                key: key(1),
+                chunk: chunk(b"one"),
+            });
+            storage.process(request).unwrap();
+            if subscriber.poll(zmq::POLLIN, 100).unwrap() > 0 {
+                break subscriber.recv_multipart(0).unwrap();
+            }
+        };
+        let event = Event {
+            table: s!("chunks"),
+            key: key(1),
+            chunk_id,
+            operation: Operation::Store,
+        };
+        assert_eq!(frames, event.to_frames().unwrap());
+        while subscriber.recv_multipart(zmq::DONTWAIT).is_ok() {}
+
+        delete().unwrap();
+        let event = Event {
+            operation: Operation::Delete,
+            ..event
+        };
+        assert_eq!(subscriber.recv_multipart(0).unwrap(), event.to_frames().unwrap());
+
+        // Nothing is published for absent keys
+        delete().unwrap();
+        assert_eq!(subscriber.poll(zmq::POLLIN, 100).unwrap(), 0);
+    }
+
+    #[test]
+    fn shutdown() {
+        let control = ZMQ_CONTEXT.socket(zmq::PULL).unwrap();
+        control.bind(CONTROL_ENDPOINT).unwrap();
+        let storage = Storage::init(&config(), None).unwrap();
+
+        assert_eq!(storage.process(Request::Shutdown), Ok(Reply::Success));
+        assert_eq!(control.recv_bytes(0).unwrap(), b"TERMINATE");
+
+        storage.shutdown().unwrap();
+        let err = storage.process(Request::Tables).unwrap_err();
+        assert_eq!(err, DaemonError::ShuttingDown);
+        assert_eq!(err.failure_code(), FailureCode::ShuttingDown);
+    }
+
+    /// Session delivering a single broken frame and recording the replies
+    struct BrokenFrameSession(Rc<RefCell<Vec<Vec<u8>>>>);
+
+    impl SendRecvMessage for BrokenFrameSession {
+        fn recv_raw_message(&mut self) -> Result<Vec<u8>, transport::Error> {
+            Err(transport::Error::FrameBroken("test frame is broken"))
+        }
+
+        fn send_raw_message(&mut self, raw: &[u8]) -> Result<usize, transport::Error> {
+            self.0.borrow_mut().push(raw.to_vec());
+            Ok(raw.len())
+        }
+
+        fn recv_routed_message(&mut self) -> Result<RoutedFrame, transport::Error> {
+            unreachable!("worker sessions are not routed")
+        }
+
+        fn send_routed_message(
+            &mut self,
+            _source: &[u8],
+            _route: &[u8],
+            _dest: &[u8],
+            _raw: &[u8],
+        ) -> Result<usize, transport::Error> {
+            unreachable!("worker sessions are not routed")
+        }
+
+        fn into_any(self: Box<Self>) -> Box<dyn Any> { self }
+    }
+
+    #[test]
+    fn framing_error() {
+        let sent = Rc::new(RefCell::new(vec![]));
+        let session = BrokenFrameSession(Rc::clone(&sent));
+        let storage = Arc::new(Storage::init(&config(), None).unwrap());
+        let mut worker = Worker::with(s!("test"), Box::new(session), None, storage);
+
+        let err = worker.run().unwrap_err();
+        assert!(!is_fatal(&err));
+        let sent = sent.borrow();
+        assert_eq!(sent.len(), 1);
+        let reply = Reply::create_unmarshaller().unmarshall(sent[0].as_slice()).unwrap();
+        match &*reply {
+            Reply::Failure(failure) => {
+                assert_eq!(failure.code, rpc::FailureCode::Framing)
+            }
+            reply => panic!("unexpected reply {}", reply),
+        }
+    }
+
+    #[test]
+    fn fatal_errors() {
+        for err in [zmq::Error::ETERM, zmq::Error::ENOTSOCK, zmq::Error::EFSM] {
+            assert!(is_fatal(&ClientError::from(err)), "{} must be fatal", err);
+        }
+        for err in [zmq::Error::EAGAIN, zmq::Error::EINTR] {
+            assert!(!is_fatal(&ClientError::from(err)), "{} must be recoverable", err);
+        }
+        assert!(!is_fatal(&ClientError::Transport(transport::Error::FrameBroken("test"))));
+        assert!(!is_fatal(&ClientError::UnexpectedRequest));
+    }
+
+    /// Backend running each transaction twice, as backends do when retrying
+    /// conflicting transactions
+    struct RetryingBackend(Box<dyn StorageBackend>);
+
+    impl StorageBackend for RetryingBackend {
+        fn open_table(&self, name: &str) -> Result<Box<dyn Table>, BackendError> {
+            self.0.open_table(name)
+        }
+
+        fn table_names(&self) -> Result<BTreeSet<String>, BackendError> { self.0.table_names() }
+
+        fn drop_table(&self, name: &str) -> Result<bool, BackendError> { self.0.drop_table(name) }
+
+        fn transaction(&self, tables: &[&str], tx: &TxFn) -> Result<(), DaemonError> {
+            self.0.transaction(tables, &|view| {
+                tx(view)?;
+                tx(view)
+            })
+        }
+
+        fn flush(&self) -> Result<(), BackendError> { self.0.flush() }
+    }
+
+    #[test]
+    fn sled_transaction() {
+        let dir = std::env::temp_dir().join(format!("stored-tx-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let config = Config {
+            backend: BackendType::Sled,
+            data_dir: dir.clone(),
+            databases: HashSet::from([s!("chunks"), s!("index")]),
+            ..config()
+        };
+        let mut storage = Storage::init(&config, None).unwrap();
+        let chunk_id = chunk(b"one").consensus_commit();
+        let transaction = |ops| Request::Transaction(TransactionReq { ops });
+        let insert = |table: &str, item| {
+            TxOp::Insert(InsertReq {
+                table: table.to_owned(),
+                key: key(1),
+                item,
+            })
+        };
+
+        let ops = vec![
+            TxOp::Store(StoreReq {
+                table: s!("chunks"),
+                key: key(1),
+                chunk: chunk(b"one"),
+            }),
+            insert("index", key(2)),
+        ];
+        assert_eq!(
+            storage.process(transaction(ops)),
+            Ok(Reply::TxResults(vec![TxResult::Stored(chunk_id), TxResult::Inserted]))
+        );
+
+        // Inserting a member into a chunk aborts the whole transaction
+        let index = RetrieveReq {
+            table: s!("index"),
+            key: key(1),
+        };
+        let ops = vec![TxOp::Delete(index.clone()), insert("chunks", key(3))];
+        let err = storage.process(transaction(ops)).unwrap_err();
+        assert_eq!(err, DaemonError::TxAborted {
+            op: 1,
+            err: Box::new(DaemonError::ValueTypeMismatch {
+                key: key(1),
+                expected: "set"
+            })
+        });
+        assert_eq!(err.failure_code(), FailureCode::ValueTypeMismatch);
+        assert_eq!(storage.process(Request::Members(index)), Ok(Reply::Members(bset![key(2)])));
+
+        // Results of an attempt retried by the backend are not reported
+        let db = mem::replace(&mut storage.db, Box::new(MemoryBackend::new()));
+        storage.db = Box::new(RetryingBackend(db));
+        let ops = vec![insert("index", key(3))];
+        assert_eq!(
+            storage.process(transaction(ops)),
+            Ok(Reply::TxResults(vec![TxResult::Inserted]))
+        );
+
+        drop(storage);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Table which gets a value written by a concurrent request right after
+    /// the value is read
+    struct RacingTable {
+        inner: Arc<dyn Table>,
+        race: Mutex<Option<(Slice32, Vec<u8>)>>,
+    }
+
+    impl Table for RacingTable {
+        fn len(&self) -> Result<usize, BackendError> { self.inner.len() }
+
+        fn get(&self, key: Slice32) -> Result<Option<Vec<u8>>, BackendError> {
+            let value = self.inner.get(key)?;
+            if let Some((key, value)) = self.race.lock().unwrap().take() {
+                self.inner.insert(key, &value)?;
+            }
+            Ok(value)
+        }
+
+        fn insert(&self, key: Slice32, value: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
+            self.inner.insert(key, value)
+        }
+
+        fn remove(&self, key: Slice32) -> Result<Option<Vec<u8>>, BackendError> {
+            self.inner.remove(key)
+        }
+
+        fn compare_and_swap(
+            &self,
+            key: Slice32,
+            old: Option<&[u8]>,
+            new: Option<&[u8]>,
+        ) -> Result<Result<(), Option<Vec<u8>>>, BackendError> {
+            self.inner.compare_and_swap(key, old, new)
+        }
+
+        fn clear(&self) -> Result<(), BackendError> { self.inner.clear() }
+
+        fn apply_batch(&self, items: Vec<(Slice32, Vec<u8>)>) -> Result<(), BackendError> {
+            self.inner.apply_batch(items)
+        }
+
+        fn range<'me>(
+            &'me self,
+            start: Bound<Slice32>,
+            end: Bound<Slice32>,
+        ) -> Box<dyn Iterator<Item = Result<backend::Entry, BackendError>> + 'me> {
+            self.inner.range(start, end)
+        }
+
+        fn flush(&self) -> Result<(), BackendError> { self.inner.flush() }
+    }
+
+    #[test]
+    fn compare_and_swap() {
+        let storage = Storage::init(&config(), None).unwrap();
+        let id = |data: &[u8]| chunk(data).consensus_commit();
+        let cas = |expected: Option<&[u8]>, data: &[u8]| {
+            storage.process(Request::CompareAndSwap(CasReq {
+                table: s!("chunks"),
+                key: key(1),
+                expected: expected.map(id),
+                chunk: chunk(data),
+            }))
+        };
+        let store_if_absent = |data: &[u8]| {
+            storage.process(Request::StoreIfAbsent(StoreReq {
+                table: s!("chunks"),
+                key: key(1),
+                chunk: chunk(data),
+            }))
+        };
+        let retrieve = || {
+            storage.process(Request::Retrieve(RetrieveReq {
+                table: s!("chunks"),
+                key: key(1),
+            }))
+        };
+
+        assert_eq!(cas(Some(b"one"), b"two"), Ok(Reply::Mismatch(None)));
+        assert_eq!(store_if_absent(b"one"), Ok(Reply::ChunkId(id(b"one"))));
+        assert_eq!(store_if_absent(b"two"), Ok(Reply::Mismatch(Some(id(b"one")))));
+        assert_eq!(cas(None, b"two"), Ok(Reply::Mismatch(Some(id(b"one")))));
+        assert_eq!(cas(Some(b"two"), b"three"), Ok(Reply::Mismatch(Some(id(b"one")))));
+        assert_eq!(cas(Some(b"one"), b"two"), Ok(Reply::ChunkId(id(b"two"))));
+        assert_eq!(retrieve(), Ok(Reply::Chunk(chunk(b"two"))));
+
+        // Value changed after it was read fails the swap and is reported
+        let inner = storage.table("chunks").unwrap();
+        let racing = RacingTable {
+            inner,
+            race: Mutex::new(Some((key(1), b"other".to_vec()))),
+        };
+        storage.tables_mut().insert(s!("chunks"), Arc::new(racing));
+        assert_eq!(cas(Some(b"two"), b"three"), Ok(Reply::Mismatch(Some(id(b"other")))));
+        assert_eq!(retrieve(), Ok(Reply::Chunk(chunk(b"other"))));
+    }
+
+    #[test]
+    fn content_addressed() {
+        let config = Config {
+            content_addressed: bset![s!("objects")],
+            ..config()
+        };
+        let storage = Storage::init(&config, None).unwrap();
+        let chunk_id = chunk(b"one").consensus_commit();
+        let store_content = |table: &str| {
+            storage.process(Request::StoreContent(StoreContentReq {
+                table: table.to_owned(),
+                chunk: chunk(b"one"),
+            }))
+        };
+
+        assert_eq!(store_content("objects"), Ok(Reply::ChunkId(chunk_id)));
+        assert_eq!(store_content("objects"), Ok(Reply::ChunkId(chunk_id)));
+        assert_eq!(storage.process(Request::Count(s!("objects"))), Ok(Reply::Count(1)));
+        assert_eq!(store_content("chunks"), Err(DaemonError::NotContentAddressed(s!("chunks"))));
+
+        // Chunk is verified against its key on every read
+        let key = chunk_id.into_slice32();
+        storage.table("objects").unwrap().insert(key, b"two").unwrap();
+        let req = RetrieveReq {
+            table: s!("objects"),
+            key,
+        };
+        assert_eq!(
+            storage.process(Request::Retrieve(req)),
+            Err(DaemonError::Corrupted {
+                key,
+                chunk_id: chunk(b"two").consensus_commit()
+            })
+        );
+
+        // Content-addressed tables keep their names
+        let rename = |from: &str, to: &str| {
+            storage.process(Request::RenameTable(RenameTableReq {
+                from: from.to_owned(),
+                to: to.to_owned(),
+            }))
+        };
+        let err = DaemonError::ContentAddressed(s!("objects"));
+        assert_eq!(storage.process(Request::DropTable(s!("objects"))), Err(err.clone()));
+        assert_eq!(rename("objects", "renamed"), Err(err.clone()));
+        assert_eq!(rename("chunks", "objects"), Err(err.clone()));
+        assert_eq!(err.failure_code(), FailureCode::Unsupported);
+        assert_eq!(
+            storage.process(Request::Tables),
+            Ok(Reply::Tables(bset![s!("chunks"), s!("objects")]))
+        );
+    }
+
+    #[test]
+    fn quarantine() {
+        let config = Config {
+            content_addressed: bset![s!("objects")],
+            acl: Acl::with(["*:objects:read".parse().unwrap()]),
+            ..config()
+        };
+        let storage = Storage::init(&config, None).unwrap();
+        let verify = |quarantine| {
+            Request::Verify(VerifyReq {
+                table: s!("objects"),
+                quarantine,
+            })
+        };
+        storage.table("objects").unwrap().insert(key(1), b"one").unwrap();
+
+        assert_eq!(storage.authorize(None, &verify(false)), Ok(()));
+        assert_eq!(
+            storage.authorize(None, &verify(true)),
+            Err(DaemonError::PermissionDenied {
+                table: s!("objects"),
+                permission: Permission::Admin,
+            })
+        );
+
+        let report = match storage.process(verify(false)) {
+            Ok(Reply::Verified(report)) => report,
+            reply => panic!("unexpected reply {:?}", reply),
+        };
+        assert_eq!((report.checked, report.bad.len()), (1, 1));
+        assert_eq!(storage.process(Request::Count(s!("objects"))), Ok(Reply::Count(1)));
+
+        assert_eq!(storage.process(verify(true)), Ok(Reply::Verified(report)));
+        assert_eq!(storage.process(Request::Count(s!("objects"))), Ok(Reply::Count(0)));
+        assert_eq!(
+            storage.process(Request::Count(fsck::QUARANTINE_TABLE.to_owned())),
+            Ok(Reply::Count(1))
+        );
+    }
+
+    #[test]
+    fn backup_path() {
+        let dir = std::env::temp_dir().join(format!("stored-backup-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let config = Config {
+            data_dir: dir.clone(),
+            ..config()
+        };
+        let storage = Storage::init(&config, None).unwrap();
+        let backup = |path: &str| {
+            storage.process(Request::Backup(BackupReq {
+                path: path.to_owned(),
+            }))
+        };
+
+        for path in ["", "/tmp/stored.bak", "../stored.bak", "daily/../../stored.bak", "./x"] {
+            let err = DaemonError::Backup(BackupError::InvalidPath(path.to_owned()));
+            assert_eq!(err.failure_code(), FailureCode::PermissionDenied);
+            assert_eq!(backup(path), Err(err));
+        }
+        assert_eq!(backup("daily/stored.bak"), Ok(Reply::Success));
+        assert!(dir.join(STORED_BACKUP_DIR).join("daily/stored.bak").is_file());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn restore() {
+        let source = std::env::temp_dir().join(format!("stored-source-{}", std::process::id()));
+        let target = std::env::temp_dir().join(format!("stored-target-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&source);
+        let _ = fs::remove_dir_all(&target);
+        let config = Config {
+            data_dir: source.clone(),
+            ..config()
+        };
+        let storage = Storage::init(&config, None).unwrap();
+        let chunks = (0..1500u16)
+            .map(|no| {
+                let mut key = [0u8; 32];
+                key[..2].copy_from_slice(&no.to_be_bytes());
+                (Slice32::from(key), chunk(&no.to_le_bytes()))
+            })
+            .collect::<BTreeMap<_, _>>();
+        let request = Request::StoreBatch(StoreBatchReq {
+            table: s!("chunks"),
+            chunks: chunks.clone(),
+        });
+        storage.process(request).unwrap();
+        storage.process(Request::Use(s!("empty"))).unwrap();
+        let backup = BackupReq {
+            path: s!("full.bak"),
+        };
+        assert_eq!(storage.process(Request::Backup(backup)), Ok(Reply::Success));
+        let archive = source.join(STORED_BACKUP_DIR).join("full.bak");
+
+        let config = Config {
+            backend: BackendType::Fs,
+            data_dir: target.clone(),
+            ..config
+        };
+        let storage = Storage::init(&config, None).unwrap();
+        let request = Request::Store(StoreReq {
+            table: s!("chunks"),
+            key: key(0xFF),
+            chunk: chunk(b"replaced"),
+        });
+        storage.process(request).unwrap();
+        // Storage used by the daemon can't be replaced
+        assert!(matches!(
+            backup::restore(&config, &archive),
+            Err(LaunchError::Database(BackendError::Locked(_)))
+        ));
+        drop(storage);
+
+        assert_eq!(backup::restore(&config, &archive), Ok(1500));
+        let storage = Storage::init(&config, None).unwrap();
+        assert_eq!(
+            storage.process(Request::Tables),
+            Ok(Reply::Tables(bset![s!("chunks"), s!("empty")]))
+        );
+        assert_eq!(storage.process(Request::Count(s!("chunks"))), Ok(Reply::Count(1500)));
+        let (key, chunk) = chunks.iter().last().unwrap();
+        let req = RetrieveReq {
+            table: s!("chunks"),
+            key: *key,
+        };
+        assert_eq!(storage.process(Request::Retrieve(req)), Ok(Reply::Chunk(chunk.clone())));
+        assert!(target.join(format!("{}.old", STORED_FS_DIR)).is_dir());
+
+        // Restoring into a fresh data directory does not keep an empty storage
+        let fresh = target.join("fresh");
+        let config = Config {
+            data_dir: fresh.clone(),
+            ..config
+        };
+        assert_eq!(backup::restore(&config, &archive), Ok(1500));
+        assert!(fresh.join(STORED_FS_DIR).is_dir());
+        assert!(!fresh.join(format!("{}.old", STORED_FS_DIR)).exists());
+
+        fs::remove_dir_all(source).unwrap();
+        fs::remove_dir_all(target).unwrap();
+    }
+
+    #[test]
+    fn group_durability() {
+        let config = Config {
+            durability: Durability::Group,
+            flush_writes: 2,
+            ..config()
+        };
+        let storage = Storage::init(&config, None).unwrap();
+        let store = |no: u8| {
+            storage.process(Request::Store(StoreReq {
+                table: s!("chunks"),
+                key: key(no),
+                chunk: chunk(&[no]),
+            }))
+        };
+
+        store(1).unwrap();
+        assert_eq!(storage.pending.load(Ordering::Acquire), 1);
+        store(2).unwrap();
+        assert_eq!(storage.pending.load(Ordering::Acquire), 0);
+
+        store(3).unwrap();
+        storage.flush_pending().unwrap();
+        assert_eq!(storage.pending.load(Ordering::Acquire), 0);
+        store(4).unwrap();
+        assert_eq!(storage.process(Request::Flush), Ok(Reply::Success));
+        assert_eq!(storage.pending.load(Ordering::Acquire), 0);
     }
 }